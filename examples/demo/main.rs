@@ -14,7 +14,7 @@ mod termion;
 #[cfg(feature = "termwiz")]
 mod termwiz;
 
-use std::{error::Error, num::Wrapping as w, path::PathBuf, time::Duration};
+use std::{error::Error, num::Wrapping as w, path::PathBuf, sync::Arc, time::Duration};
 
 use ratatui::{
     backend::Backend,
@@ -72,7 +72,8 @@ fn size() -> Rect {
 impl<'a> App<'a> {
     pub fn new<B: Backend>(title: &'a str, _: &mut Terminal<B>) -> App<'a> {
         let ada = "./assets/Ada.png";
-        let dyn_img = image::io::Reader::open(ada).unwrap().decode().unwrap();
+        // The decoded image is shared by all widgets, instead of copied.
+        let dyn_img = Arc::new(image::io::Reader::open(ada).unwrap().decode().unwrap());
 
         let mut picker = Picker::from_termios(None).unwrap();
 
@@ -134,23 +135,24 @@ impl<'a> App<'a> {
             }
             'i' => {
                 let next = match self.picker.protocol_type() {
-                    #[cfg(not(feature = "sixel"))]
+                    #[cfg(not(any(feature = "sixel", feature = "sixel-native")))]
                     ProtocolType::Halfblocks => ProtocolType::Kitty,
-                    #[cfg(feature = "sixel")]
+                    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
                     ProtocolType::Halfblocks => ProtocolType::Sixel,
-                    #[cfg(feature = "sixel")]
+                    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
                     ProtocolType::Sixel => ProtocolType::Kitty,
-                    ProtocolType::Kitty => ProtocolType::Halfblocks,
+                    ProtocolType::Kitty => ProtocolType::Iterm,
+                    ProtocolType::Iterm | ProtocolType::Custom(_) => ProtocolType::Halfblocks,
                 };
-                self.picker.set(next);
+                self.picker.set(next).unwrap();
 
                 self.image_static = self
                     .picker
                     .new_static_fit(self.image_source.image.clone(), size(), Resize::Fit)
                     .unwrap();
 
-                self.image_fit_state.reset();
-                self.image_crop_state.reset();
+                self.picker.switch_state(&mut self.image_fit_state);
+                self.picker.switch_state(&mut self.image_crop_state);
             }
             'o' => {
                 let path = match self.image_source_path.to_str() {
@@ -19,14 +19,13 @@ use ratatui::{
 };
 use ratatui_image::{
     picker::Picker,
-    protocol::{ImageSource, ResizeProtocol},
+    protocol::{kitty::KittySequences, ResizeProtocol},
     Resize, ResizeImage,
 };
 
 struct App {
     pub filename: String,
     pub picker: Picker,
-    pub image_source: ImageSource,
     pub image_state: Box<dyn ResizeProtocol>,
 }
 
@@ -37,14 +36,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let image = image::io::Reader::open(&filename)?.decode()?;
 
     let mut picker = Picker::from_termios(Some(Rgb::<u8>([255, 0, 255])))?;
+    let sequences = KittySequences::new();
+    picker.set_kitty_sequences(Some(sequences.clone()));
 
-    let image_source = ImageSource::new(image.clone(), picker.font_size());
     let image_state = picker.new_state(image);
 
     let mut app = App {
         filename,
         picker,
-        image_source,
         image_state,
     };
 
@@ -58,6 +57,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let tick_rate = Duration::from_millis(1000);
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        sequences.write_to(terminal.backend_mut())?;
 
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
@@ -70,8 +70,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             'q' => break,
                             ' ' => {
                                 app.picker.cycle_protocols();
-                                app.image_state =
-                                    app.picker.new_state(app.image_source.image.clone());
+                                app.picker.switch_state(&mut app.image_state);
                             }
                             _ => {}
                         },
@@ -102,7 +101,11 @@ fn ui(f: &mut Frame<CrosstermBackend<Stdout>>, app: &mut App) {
     let block_top = Block::default()
         .borders(Borders::ALL)
         .title("ratatui-image");
-    let dyn_img = &app.image_source.image;
+    let dyn_img = &app
+        .image_state
+        .source()
+        .expect("states from the picker keep their source")
+        .image;
     let lines = vec![
         Line::from(format!(
             "Terminal: {:?}, font size: {:?}",
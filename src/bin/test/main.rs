@@ -9,18 +9,12 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use image::Rgb;
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     text::Line,
-    widgets::{Block, Borders, Paragraph, Wrap, Widget, StatefulWidget},
-    Frame, Terminal, style::Style, prelude::Rect,
-};
-use ratatui_image::{
-    picker::Picker,
-    protocol::{ImageSource, ResizeProtocol, iterm, Protocol},
-    Resize, ResizeImage, FixedImage,
+    widgets::{Paragraph, Wrap, Widget, StatefulWidget},
+    Frame, Terminal, prelude::Rect,
 };
 
 struct App {
@@ -58,11 +52,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             if let Event::Key(key) = event::read()? {
                 if key.kind == KeyEventKind::Press {
                     match key.code {
-                        KeyCode::Char(c) => match c {
-                            'q' => break,
-                            _ => {}
-                        },
-                        KeyCode::Esc => break,
+                        KeyCode::Char('q') | KeyCode::Esc => break,
                         KeyCode::Enter => (),
                         _ => {}
                     }
@@ -137,7 +127,9 @@ impl Widget for Im {
     }
 }
 
+#[allow(dead_code)]
 struct Mask {}
+#[allow(dead_code)]
 struct MaskState {
     pub progress:f32,
 }
@@ -165,16 +157,15 @@ impl StatefulWidget for Mask {
         // try_skip, must do before resetting cells
         for i in start_x..width {
             if buf.get(i+area.x, start_y+area.y).symbol.eq(" "){
-                let mut diff = 1.0 / (area.width as f32 * area.height as f32) as f32;
+                let mut diff = 1.0 / (area.width as f32 * area.height as f32);
                 diff *= 0.5;
                 state.progress += diff;
             }
         }
         let len = buf.content.len();
         for index in 0..len {
-            let should_clear:bool;
             let (x,y) = buf.pos_of(index);
-            should_clear = (!is_in(x,y,start_x,start_y)) && area.intersects(Rect::new(x+area.x,y+area.y,1,1));
+            let should_clear:bool = (!is_in(x,y,start_x,start_y)) && area.intersects(Rect::new(x+area.x,y+area.y,1,1));
             if should_clear {
                 if buf.get(x+area.x, y+area.y).symbol.eq(" "){
                     continue;
@@ -187,11 +178,11 @@ impl StatefulWidget for Mask {
         fn is_in(x:u16,y:u16,x_:u16,y_:u16)-> bool {
             // if this is true, should NOT clear;
             if y < y_ {
-                return true;
+                true
             } else if y==y_{
-                return x<=x_;
+                x<=x_
             } else {
-                return false;
+                false
             }
         }
     }
@@ -0,0 +1,487 @@
+//! Encoding cache that can be shared between [crate::protocol::ResizeProtocol] states.
+//!
+//! Several states built from the same image (e.g. the same avatar in many list rows) would each
+//! resize and encode the exact same output. With an [EncodingCache] set on the
+//! [crate::picker::Picker], every state built by it looks up its encoding in the cache first.
+//!
+//! The cache can be backed by a [DiskCache], so that sixel, kitty and iTerm2 encodings survive
+//! across application launches.
+//!
+//! ```rust
+//! use ratatui_image::{cache::{DiskCache, EncodingCache}, picker::{Picker, ProtocolType}};
+//!
+//! let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+//! // Keep up to 32MiB of encoded images in memory, and 256MiB on disk.
+//! let mut cache = EncodingCache::new(32 * 1024 * 1024);
+//! if let Some(disk) = DiskCache::xdg(256 * 1024 * 1024) {
+//!     cache = cache.disk(disk);
+//! }
+//! picker.set_encoding_cache(Some(cache));
+//! ```
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use image::Rgb;
+use ratatui::layout::Rect;
+
+use crate::{
+    picker::ProtocolType,
+    quality::{Quality, SixelOptions},
+    FontSize, ImageSource, Resize, Result,
+};
+
+/// Identifies one encoding output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EncodingKey {
+    /// The [ImageSource::hash] of the original image.
+    pub hash: u64,
+    /// The target rect in cells.
+    pub rect: Rect,
+    pub font_size: FontSize,
+    pub resize: Resize,
+    pub background_color: Option<Rgb<u8>>,
+    pub protocol: ProtocolType,
+    pub quality: Quality,
+    pub sixel: SixelOptions,
+    /// Whether the encoding may be stored in a [DiskCache], see [ImageSource::with_id].
+    pub persistent: bool,
+}
+
+impl EncodingKey {
+    pub fn new(
+        source: &ImageSource,
+        rect: Rect,
+        resize: &Resize,
+        background_color: Option<Rgb<u8>>,
+        protocol: ProtocolType,
+    ) -> EncodingKey {
+        EncodingKey {
+            hash: source.hash,
+            rect,
+            font_size: source.font_size,
+            resize: resize.clone(),
+            background_color,
+            protocol,
+            quality: Quality::default(),
+            sixel: SixelOptions::default(),
+            persistent: source.persistent,
+        }
+    }
+
+    /// Set the [Quality] that the encoding was made with.
+    pub fn quality(mut self, quality: Quality) -> EncodingKey {
+        self.quality = quality;
+        self
+    }
+
+    /// Set the [SixelOptions] that the encoding was made with.
+    pub fn sixel(mut self, options: SixelOptions) -> EncodingKey {
+        self.sixel = options;
+        self
+    }
+}
+
+/// Encoded output that can be stored in an [EncodingCache].
+pub(crate) trait Encoded: Any + Clone + Send {
+    /// Approximate size in bytes, counted against the cache budget.
+    fn byte_size(&self) -> usize;
+    /// The data to store in a [DiskCache], if this encoding can be persisted.
+    fn to_disk(&self) -> Option<&str> {
+        None
+    }
+    /// Restore from [Encoded::to_disk] data.
+    fn from_disk(_data: String, _key: &EncodingKey) -> Option<Self> {
+        None
+    }
+}
+
+struct Entry {
+    value: Box<dyn Any + Send>,
+    size: usize,
+    last_used: u64,
+}
+
+struct Inner {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<EncodingKey, Entry>,
+}
+
+/// LRU cache of encoded images with a byte budget.
+///
+/// Cloning is cheap and clones share the same storage.
+#[derive(Clone)]
+pub struct EncodingCache {
+    inner: Arc<Mutex<Inner>>,
+    disk: Option<DiskCache>,
+}
+
+impl EncodingCache {
+    /// Create a cache that holds up to `budget` bytes of encoded data.
+    pub fn new(budget: usize) -> EncodingCache {
+        EncodingCache {
+            inner: Arc::new(Mutex::new(Inner {
+                budget,
+                used: 0,
+                tick: 0,
+                entries: HashMap::new(),
+            })),
+            disk: None,
+        }
+    }
+
+    /// Back the cache with a [DiskCache].
+    ///
+    /// Encodings that are not in memory are looked up on disk, and new encodings are written to
+    /// disk as well. Use a budget of `0` to only cache on disk.
+    pub fn disk(mut self, disk: DiskCache) -> EncodingCache {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Number of cached encodings.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes currently used by cached encodings.
+    pub fn bytes_used(&self) -> usize {
+        self.lock().used
+    }
+
+    /// Remove all cached encodings.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.used = 0;
+    }
+
+    pub(crate) fn get<T: Encoded>(&self, key: &EncodingKey) -> Option<T> {
+        let mut inner = self.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key)?;
+        entry.last_used = tick;
+        entry.value.downcast_ref::<T>().cloned()
+    }
+
+    pub(crate) fn insert<T: Encoded>(&self, key: EncodingKey, value: T) {
+        let size = value.byte_size();
+        let mut inner = self.lock();
+        if size > inner.budget {
+            return;
+        }
+        inner.tick += 1;
+        let entry = Entry {
+            value: Box::new(value),
+            size,
+            last_used: inner.tick,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.used -= old.size;
+        }
+        inner.used += size;
+        while inner.used > inner.budget {
+            let lru = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match lru.and_then(|key| inner.entries.remove(&key)) {
+                Some(evicted) => inner.used -= evicted.size,
+                None => break,
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // A panic while holding the lock can't leave the map in an invalid state.
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Get the encoding for `key` from the cache, or encode it and store it in the cache.
+pub(crate) fn get_or_encode<T, F>(
+    cache: Option<&EncodingCache>,
+    key: EncodingKey,
+    encode: F,
+) -> Result<T>
+where
+    T: Encoded,
+    F: FnOnce() -> Result<T>,
+{
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return encode(),
+    };
+    if let Some(value) = cache.get(&key) {
+        return Ok(value);
+    }
+    let disk = cache.disk.as_ref().filter(|_| key.persistent);
+    if let Some(disk) = disk {
+        if let Some(value) = disk.get(&key).and_then(|data| T::from_disk(data, &key)) {
+            cache.insert(key, value.clone());
+            return Ok(value);
+        }
+    }
+    let value = encode()?;
+    if let (Some(disk), Some(data)) = (disk, value.to_disk()) {
+        disk.insert(&key, data);
+    }
+    cache.insert(key, value.clone());
+    Ok(value)
+}
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike `DefaultHasher`, its output doesn't change between Rust releases, so it can name files
+/// that outlive the program.
+pub(crate) struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Persistent cache of encoded escape sequences, one file per encoding.
+///
+/// When the total size of the files exceeds the limit, the oldest written files are deleted
+/// first (FIFO), regardless of how recently they were read. Only files named like the cache's own
+/// entries are counted and deleted, so other files in the directory are left alone.
+/// All IO errors are ignored, a failing disk cache just behaves like an empty one.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    /// Bump when the encoders change their output, to ignore stale files.
+    const VERSION: u32 = 1;
+
+    /// Cache in `dir`, holding up to `max_bytes`.
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> DiskCache {
+        DiskCache {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// Cache in `$XDG_CACHE_HOME/ratatui-image`, or `$HOME/.cache/ratatui-image`.
+    pub fn xdg(max_bytes: u64) -> Option<DiskCache> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(DiskCache::new(base.join("ratatui-image"), max_bytes))
+    }
+
+    /// The directory where the files are stored.
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    fn path(&self, key: &EncodingKey) -> PathBuf {
+        let mut hasher = Fnv::default();
+        DiskCache::VERSION.hash(&mut hasher);
+        key.hash(&mut hasher);
+        let name = format!("{:?}", key.protocol).to_lowercase();
+        self.dir.join(format!("{name}-{:016x}", hasher.finish()))
+    }
+
+    /// Whether `name` is a file name from [DiskCache::path].
+    fn is_entry(name: &str) -> bool {
+        match name.rsplit_once('-') {
+            Some((protocol, hash)) => {
+                ["sixel", "kitty", "iterm"].contains(&protocol)
+                    && hash.len() == 16
+                    && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            }
+            None => false,
+        }
+    }
+
+    fn get(&self, key: &EncodingKey) -> Option<String> {
+        fs::read_to_string(self.path(key)).ok()
+    }
+
+    fn insert(&self, key: &EncodingKey, data: &str) {
+        if data.len() as u64 > self.max_bytes || fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+        // Write and rename, so that a concurrent reader never sees a partial file.
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        if fs::write(&tmp, data).is_err() || fs::rename(&tmp, &path).is_err() {
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let meta = entry.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), entry.path()))
+            })
+            .filter(|(_, _, path)| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, DiskCache::is_entry)
+            })
+            .collect();
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Bytes(usize);
+
+    impl Encoded for Bytes {
+        fn byte_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn key(hash: u64) -> EncodingKey {
+        EncodingKey {
+            hash,
+            rect: Rect::new(0, 0, 10, 10),
+            font_size: (1, 1),
+            resize: Resize::Fit,
+            background_color: None,
+            protocol: ProtocolType::Kitty,
+            quality: Quality::default(),
+            sixel: SixelOptions::default(),
+            persistent: true,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = EncodingCache::new(100);
+        cache.insert(key(1), Bytes(40));
+        cache.insert(key(2), Bytes(40));
+        assert!(cache.get::<Bytes>(&key(1)).is_some());
+
+        cache.insert(key(3), Bytes(40));
+        assert_eq!(2, cache.len());
+        assert_eq!(80, cache.bytes_used());
+        assert!(cache.get::<Bytes>(&key(1)).is_some());
+        assert!(cache.get::<Bytes>(&key(2)).is_none());
+        assert!(cache.get::<Bytes>(&key(3)).is_some());
+    }
+
+    #[test]
+    fn encodes_once() {
+        let cache = EncodingCache::new(100);
+        let mut count = 0;
+        for _ in 0..3 {
+            get_or_encode(Some(&cache), key(1), || {
+                count += 1;
+                Ok(Bytes(10))
+            })
+            .unwrap();
+        }
+        assert_eq!(1, count);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Text(String);
+
+    impl Encoded for Text {
+        fn byte_size(&self) -> usize {
+            self.0.len()
+        }
+        fn to_disk(&self) -> Option<&str> {
+            Some(&self.0)
+        }
+        fn from_disk(data: String, _key: &EncodingKey) -> Option<Self> {
+            Some(Text(data))
+        }
+    }
+
+    #[test]
+    fn fnv() {
+        let mut hasher = Fnv::default();
+        hasher.write(b"a");
+        assert_eq!(0xaf63_dc4c_8601_ec8c, hasher.finish());
+    }
+
+    #[test]
+    fn disk_cache() {
+        let dir = std::env::temp_dir().join(format!("ratatui-image-test-{}", std::process::id()));
+        let disk = DiskCache::new(&dir, 10);
+        // Not an entry, so neither counted nor evicted.
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes"), "some other file").unwrap();
+        let encode = |text: &str| {
+            let cache = EncodingCache::new(0).disk(disk.clone());
+            get_or_encode(Some(&cache), key(1), || Ok(Text(text.to_string()))).unwrap()
+        };
+        assert_eq!(Text("first".into()), encode("first"));
+        // Memory budget is zero, so this can only come from disk.
+        assert_eq!(Text("first".into()), encode("second"));
+
+        // Over the limit of 10 bytes, the oldest file is evicted.
+        let cache = EncodingCache::new(0).disk(disk.clone());
+        get_or_encode(Some(&cache), key(2), || Ok(Text("012345".into()))).unwrap();
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        assert!(dir.join("notes").exists());
+        assert_eq!(Text("again".into()), encode("again"));
+
+        // Ids from ImageSource::with_id never go to disk.
+        let cache = EncodingCache::new(0).disk(disk.clone());
+        let user_id = EncodingKey {
+            persistent: false,
+            ..key(3)
+        };
+        get_or_encode(Some(&cache), user_id, || Ok(Text("id".into()))).unwrap();
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
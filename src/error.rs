@@ -0,0 +1,103 @@
+//! Crate-wide error type
+
+use std::{fmt, io};
+
+/// Errors that can occur while picking a protocol, or resizing and encoding images.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// IO error, including termios query errors.
+    Io(io::Error),
+    /// The terminal reported zero pixels or cells, so no font size can be derived.
+    ZeroFontSize,
+    /// The requested graphics protocol is not supported by the terminal.
+    UnsupportedProtocol(String),
+    /// Sixel encoding error (from libsixel).
+    #[cfg(feature = "sixel")]
+    Sixel(SixelError),
+    /// Image codec error, e.g. when encoding PNG for iTerm2.
+    Image(image::ImageError),
+    /// The terminal did not answer a query in time.
+    Timeout,
+    /// The terminal reported an error for a kitty image, e.g. `ENOSPC:...` or `EBADPNG:...`.
+    Kitty {
+        /// The id of the image, see [crate::protocol::kitty::KittyId].
+        id: u32,
+        /// The terminal's message, starting with an error code like `ENOSPC`.
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {err}"),
+            Error::ZeroFontSize => write!(f, "font size has zero value"),
+            Error::UnsupportedProtocol(msg) => write!(f, "unsupported protocol: {msg}"),
+            #[cfg(feature = "sixel")]
+            Error::Sixel(err) => write!(f, "sixel error: {err}"),
+            Error::Image(err) => write!(f, "image error: {err}"),
+            Error::Timeout => write!(f, "terminal query timed out"),
+            Error::Kitty { id, message } => write!(f, "kitty image {id}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            #[cfg(feature = "sixel")]
+            Error::Sixel(err) => Some(err),
+            Error::Image(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<rustix::io::Errno> for Error {
+    fn from(errno: rustix::io::Errno) -> Self {
+        Error::Io(errno.into())
+    }
+}
+
+#[cfg(feature = "sixel")]
+impl From<sixel_bytes::SixelError> for Error {
+    fn from(err: sixel_bytes::SixelError) -> Self {
+        Error::Sixel(SixelError(err))
+    }
+}
+
+/// A libsixel error, which doesn't implement [std::error::Error] by itself.
+#[cfg(feature = "sixel")]
+#[derive(Debug)]
+pub struct SixelError(pub sixel_bytes::SixelError);
+
+#[cfg(feature = "sixel")]
+impl fmt::Display for SixelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "sixel")]
+impl std::error::Error for SixelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.0 {
+            sixel_bytes::SixelError::Utf8(err) => Some(err),
+            sixel_bytes::SixelError::Sixel(_) => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        Error::Image(err)
+    }
+}
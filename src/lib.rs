@@ -21,7 +21,7 @@
 //!
 //! fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     // It is highly recommended to use Picker::from_termios() instead!
-//!     let mut picker = Picker::new((7, 16), ProtocolType::Sixel, None)?;
+//!     let mut picker = Picker::new((7, 16), ProtocolType::Halfblocks, None)?;
 //!
 //!     let dyn_img = image::io::Reader::open("./assets/Ada.png")?.decode()?;
 //!     let image = picker.new_state(dyn_img);
@@ -52,6 +52,9 @@
 //! the protocols. Its only upside is that it is stateless (in terms of ratatui), and thus is not
 //! performance-impacted by render area resizes.
 //!
+//! The [thread::ThreadImage] widget works like [ResizeImage], but resizes and encodes on a
+//! background thread, so that big images don't block rendering.
+//!
 //! # Examples
 //!
 //! See the [crate::picker::Picker] helper and [`examples/demo`](./examples/demo/main.rs).
@@ -59,25 +62,24 @@
 //!
 //! # Features
 //! * `sixel` (default) compiles with libsixel.
+//! * `sixel-native` enables sixel with a pure Rust encoder instead, e.g. for static musl builds.
+//!   libsixel is used if both are enabled.
 //! * `rustix` (default) enables [picker::Picker::from_termios] to guess which graphics protocol to use and what
-//! font-size the terminal has.
+//!   font-size the terminal has.
 //! * `crossterm` / `termion` / `termwiz` should match your ratatui backend. `termwiz` is not
-//! working correctly with ratatu-image!
+//!   working correctly with ratatu-image!
 //! * `serde` for `#[derive]`s on [picker::ProtocolType] for convenience, because it might be
-//! useful to save it in some user configuration.
+//!   useful to save it in some user configuration.
 //!
 //! [Ratatui]: https://github.com/ratatui-org/ratatui
 //! [Sixel]: https://en.wikipedia.org/wiki/Sixel
 //! [Ratatui PR for cell skipping]: https://github.com/ratatui-org/ratatui/pull/215
 //! [Ratatui PR for getting window size]: https://github.com/ratatui-org/ratatui/pull/276
-use std::{
-    cmp::{max, min},
-    error::Error,
-};
+use std::cmp::{max, min};
 
 use image::{
     imageops::{self, FilterType},
-    DynamicImage, ImageBuffer, Rgb,
+    DynamicImage, ImageBuffer, Rgb, Rgba,
 };
 use protocol::{ImageSource, Protocol, ResizeProtocol};
 use ratatui::{
@@ -86,10 +88,20 @@ use ratatui::{
     widgets::{StatefulWidget, Widget},
 };
 
+pub mod cache;
+mod error;
 pub mod picker;
 pub mod protocol;
+pub mod quality;
+pub mod stats;
+pub mod thread;
 
-type Result<T> = std::result::Result<T, Box<dyn Error>>;
+pub use error::Error;
+#[cfg(feature = "sixel")]
+pub use error::SixelError;
+
+/// Result type with the crate-wide [Error].
+pub type Result<T> = std::result::Result<T, Error>;
 
 /// The terminal's font size in `(width, height)`
 pub type FontSize = (u16, u16);
@@ -112,11 +124,19 @@ pub type FontSize = (u16, u16);
 /// ```
 pub struct FixedImage<'a> {
     image: &'a dyn Protocol,
+    layer: Layer,
 }
 
 impl<'a> FixedImage<'a> {
     pub fn new(image: &'a dyn Protocol) -> FixedImage<'a> {
-        FixedImage { image }
+        FixedImage {
+            image,
+            layer: Layer::default(),
+        }
+    }
+    pub fn layer(mut self, layer: Layer) -> FixedImage<'a> {
+        self.layer = layer;
+        self
     }
 }
 
@@ -126,7 +146,7 @@ impl<'a> Widget for FixedImage<'a> {
             return;
         }
 
-        self.image.render(area, buf);
+        self.image.render_layer(area, buf, self.layer);
     }
 }
 
@@ -152,6 +172,8 @@ impl<'a> Widget for FixedImage<'a> {
 pub struct ResizeImage {
     resize: Resize,
     background_color: Option<Rgb<u8>>,
+    layer: Layer,
+    pan: (u32, u32),
 }
 
 impl ResizeImage {
@@ -159,22 +181,69 @@ impl ResizeImage {
         ResizeImage {
             resize: Resize::Fit,
             background_color,
+            layer: Layer::default(),
+            pan: (0, 0),
         }
     }
     pub fn resize(mut self, resize: Resize) -> ResizeImage {
         self.resize = resize;
         self
     }
+    pub fn layer(mut self, layer: Layer) -> ResizeImage {
+        self.layer = layer;
+        self
+    }
+    /// Pan [Resize::Crop] images, see [ResizeProtocol::set_pan].
+    ///
+    /// Only works with kitty states that scale on the terminal, with
+    /// [protocol::kitty::KittyScaling::Terminal]. Other states ignore it.
+    pub fn pan(mut self, pan: (u32, u32)) -> ResizeImage {
+        self.pan = pan;
+        self
+    }
 }
 
 impl StatefulWidget for ResizeImage {
     type State = Box<dyn ResizeProtocol>;
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        state.set_layer(self.layer);
+        state.set_pan(self.pan);
         state.render(&self.resize, self.background_color, area, buf)
     }
 }
 
-#[derive(Debug)]
+/// Stacking order of an image and the text around it.
+///
+/// Kitty places images with the z-index of the layer. The other protocols can't draw graphics
+/// under text, so they emulate layers below text by leaving the cells that already have text
+/// alone, so that the terminal draws the text over the image. Render images below text *after*
+/// the text widgets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Layer(i32);
+
+impl Layer {
+    /// Above text, the default.
+    pub const ABOVE: Layer = Layer(0);
+    /// Below text, but above cell background colors.
+    pub const BELOW_TEXT: Layer = Layer(-1);
+    /// Below text and cell background colors, only visible where cells have no background color.
+    pub const BACKGROUND: Layer = Layer(-1_073_741_825);
+
+    /// A kitty z-index, to stack images above each other. Negative values are below text.
+    pub const fn z(z: i32) -> Layer {
+        Layer(z)
+    }
+
+    pub fn z_index(&self) -> i32 {
+        self.0
+    }
+
+    pub fn is_below_text(&self) -> bool {
+        self.0 < 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// Resize method
 pub enum Resize {
     /// Fit to area.
@@ -202,21 +271,44 @@ impl Resize {
         background_color: Option<Rgb<u8>>,
         force: bool,
     ) -> Option<(DynamicImage, Rect)> {
-        self.needs_resize(source, current, area, force).map(|rect| {
-            let width = (rect.width * source.font_size.0) as u32;
-            let height = (rect.height * source.font_size.1) as u32;
-            // Resize/Crop/etc. but not necessarily fitting cell size
-            let mut image = self.resize_image(source, width, height);
-            // Pad to cell size
-            if image.width() != width || image.height() != height {
-                static DEFAULT_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
-                let color = background_color.unwrap_or(DEFAULT_BACKGROUND);
-                let mut bg: DynamicImage = ImageBuffer::from_pixel(width, height, color).into();
-                imageops::overlay(&mut bg, &image, 0, 0);
-                image = bg;
-            }
-            (image, rect)
-        })
+        self.needs_resize(source, current, area, force)
+            .map(|rect| (self.resize_to(source, rect, background_color), rect))
+    }
+
+    /// Resize [`ImageSource`] to `rect` and pad it to cell size.
+    fn resize_to(
+        &self,
+        source: &ImageSource,
+        rect: Rect,
+        background_color: Option<Rgb<u8>>,
+    ) -> DynamicImage {
+        static DEFAULT_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+        let color = background_color.unwrap_or(DEFAULT_BACKGROUND);
+        self.resize_to_padded(source, rect, Some(color))
+    }
+
+    /// Like [Resize::resize_to], but pad with transparent pixels if there is no
+    /// `background_color`, keeping the transparency of the image.
+    fn resize_to_padded(
+        &self,
+        source: &ImageSource,
+        rect: Rect,
+        background_color: Option<Rgb<u8>>,
+    ) -> DynamicImage {
+        let width = (rect.width * source.font_size.0) as u32;
+        let height = (rect.height * source.font_size.1) as u32;
+        // Resize/Crop/etc. but not necessarily fitting cell size
+        let mut image = self.resize_image(source, width, height);
+        // Pad to cell size
+        if image.width() != width || image.height() != height {
+            let mut bg: DynamicImage = match background_color {
+                Some(color) => ImageBuffer::from_pixel(width, height, color).into(),
+                None => ImageBuffer::from_pixel(width, height, Rgba([0u8; 4])).into(),
+            };
+            imageops::overlay(&mut bg, &image, 0, 0);
+            image = bg;
+        }
+        image
     }
 
     /// Check if [`ImageSource`]'s "desired" fits into `area` and is different than `current`.
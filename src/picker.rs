@@ -1,36 +1,57 @@
 //! Helper module to build a protocol, and swap protocols at runtime
 
+use std::sync::Arc;
+
 use image::{DynamicImage, Rgb};
-use ratatui::layout::Rect;
+use ratatui::{buffer::Buffer, layout::Rect};
 #[cfg(feature = "rustix")]
 use rustix::termios::Winsize;
-#[cfg(all(feature = "sixel", feature = "rustix"))]
-use rustix::termios::{LocalModes, OptionalActions};
+#[cfg(feature = "rustix")]
+use rustix::termios::{LocalModes, OptionalActions, SpecialCodeIndex};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-#[cfg(feature = "sixel")]
+#[cfg(any(feature = "sixel", feature = "sixel-native"))]
 use crate::protocol::sixel::{FixedSixel, SixelState};
 
 use crate::{
+    cache::EncodingCache,
     protocol::{
         halfblocks::{FixedHalfblocks, HalfblocksState},
-        kitty::{FixedKitty, KittyState},
-        Protocol, ResizeProtocol, iterm::{FixedIterm, ItermState},
+        iterm::{FixedIterm, ItermState},
+        kitty::{
+            FixedKitty, KittyAnimationState, KittyFrame, KittyIds, KittyMedium, KittyPlacement,
+            KittyResponses, KittyScaling, KittySequences, KittyState,
+        },
+        Generation, ImageHash, Protocol, ProtocolFactory, ResizeProtocol,
     },
-    FontSize, ImageSource, Resize, Result,
+    quality::{Quality, SixelOptions},
+    stats::{RenderStats, Telemetry, TelemetryTotals},
+    Error, FontSize, ImageSource, Layer, Resize, Result,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Picker {
     font_size: FontSize,
     background_color: Option<Rgb<u8>>,
     protocol_type: ProtocolType,
-    kitty_counter: u8,
+    kitty_ids: KittyIds,
+    kitty_medium: KittyMedium,
+    kitty_placement: KittyPlacement,
+    kitty_scaling: KittyScaling,
+    kitty_responses: Option<KittyResponses>,
+    kitty_sequences: Option<KittySequences>,
+    sixel_options: SixelOptions,
+    cache: Option<EncodingCache>,
+    image_hash: ImageHash,
+    generation: Generation,
+    factories: Vec<Arc<dyn ProtocolFactory>>,
+    telemetry: Telemetry,
+    quality: Quality,
 }
 
 /// Serde-friendly protocol-type enum for [Picker].
-#[derive(PartialEq, Clone, Debug, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
 #[cfg_attr(
     feature = "serde",
     derive(Deserialize, Serialize),
@@ -38,24 +59,27 @@ pub struct Picker {
 )]
 pub enum ProtocolType {
     Halfblocks,
-    #[cfg(feature = "sixel")]
+    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
     Sixel,
     Kitty,
     Iterm,
+    /// A [ProtocolFactory] registered with [Picker::register], by registration order.
+    Custom(u8),
 }
 
 impl ProtocolType {
+    /// The next built-in protocol. See [Picker::cycle_protocols] to include custom protocols.
     pub fn next(&self) -> ProtocolType {
         match self {
-            #[cfg(not(feature = "sixel"))]
+            #[cfg(not(any(feature = "sixel", feature = "sixel-native")))]
             ProtocolType::Halfblocks => ProtocolType::Iterm,
-            #[cfg(feature = "sixel")]
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
             ProtocolType::Halfblocks => ProtocolType::Sixel,
-            #[cfg(feature = "sixel")]
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
             ProtocolType::Sixel => ProtocolType::Kitty,
             ProtocolType::Kitty => ProtocolType::Iterm,
             ProtocolType::Iterm => ProtocolType::Halfblocks,
-            
+            ProtocolType::Custom(_) => ProtocolType::Halfblocks,
         }
     }
 }
@@ -67,6 +91,9 @@ impl Picker {
     /// This writes and reads from stdin momentarily. Best be called *before* initializing the
     /// terminal backend, to be safe.
     ///
+    /// Kitty images are placed with [KittyPlacement::from_env], and the quality is
+    /// [Quality::from_env].
+    ///
     /// # Example
     /// ```rust
     /// use ratatui_image::picker::Picker;
@@ -76,9 +103,22 @@ impl Picker {
     pub fn from_termios(background_color: Option<Rgb<u8>>) -> Result<Picker> {
         let stdout = rustix::stdio::stdout();
         let font_size: (u16, u16) = font_size(rustix::termios::tcgetwinsize(stdout)?)?;
-        // YLY MODIFIED: 
+        // YLY MODIFIED:
         // calling check_device_attrs() may block on read(); let's assert sixel support now
-        Picker::new(font_size, ProtocolType::Sixel, background_color)
+        let mut picker = Picker::new(font_size, ProtocolType::Sixel, background_color)?;
+        picker.kitty_placement = KittyPlacement::from_env();
+        picker.quality = Quality::from_env();
+        Ok(picker)
+    }
+
+    /// Use the [ProtocolType] guessed from `TERM` and the terminal's device attributes, and
+    /// return it.
+    ///
+    /// This writes and reads from stdin momentarily, like [Picker::from_termios].
+    #[cfg(feature = "rustix")]
+    pub fn query_protocol(&mut self) -> ProtocolType {
+        self.protocol_type = guess_protocol();
+        self.protocol_type
     }
 
     /// Create a picker from a given terminal [FontSize] and [ProtocolType].
@@ -99,81 +139,379 @@ impl Picker {
         protocol_type: ProtocolType,
         background_color: Option<Rgb<u8>>,
     ) -> Result<Picker> {
+        if let ProtocolType::Custom(index) = protocol_type {
+            // No factory has been registered yet.
+            return Err(unregistered(index));
+        }
         Ok(Picker {
             font_size,
             background_color,
             protocol_type,
-            kitty_counter: 0,
+            kitty_ids: KittyIds::new(),
+            kitty_medium: KittyMedium::default(),
+            kitty_placement: KittyPlacement::Placeholders,
+            kitty_scaling: KittyScaling::default(),
+            kitty_responses: None,
+            kitty_sequences: None,
+            sixel_options: SixelOptions::default(),
+            cache: None,
+            image_hash: ImageHash::default(),
+            generation: Generation::default(),
+            factories: Vec::new(),
+            telemetry: Telemetry::default(),
+            quality: Quality::FULL,
         })
     }
 
     /// Set a specific protocol.
-    pub fn set(&mut self, r#type: ProtocolType) {
+    ///
+    /// Fails for a [ProtocolType::Custom] that has not been registered with [Picker::register].
+    pub fn set(&mut self, r#type: ProtocolType) -> Result<()> {
+        if let ProtocolType::Custom(index) = r#type {
+            if self.factory(index).is_none() {
+                return Err(unregistered(index));
+            }
+        }
         self.protocol_type = r#type;
+        Ok(())
+    }
+
+    /// Share encodings between all states built by this picker, see [EncodingCache].
+    pub fn set_encoding_cache(&mut self, cache: Option<EncodingCache>) {
+        self.cache = cache;
+    }
+
+    /// Set how images passed to [Picker::new_state] and [Picker::new_static_fit] are hashed.
+    pub fn set_image_hash(&mut self, image_hash: ImageHash) {
+        self.image_hash = image_hash;
+    }
+
+    /// Invalidate every *state* protocol built by this picker, like [ResizeProtocol::reset].
+    ///
+    /// Useful after the terminal has been cleared, e.g. when coming back from a suspended shell.
+    /// States draw different but equivalent cells afterwards, so that ratatui redraws them even
+    /// without `Terminal::clear`.
+    pub fn invalidate_all(&self) {
+        self.generation.invalidate();
+    }
+
+    /// Trade image quality for bandwidth, see [Quality].
+    ///
+    /// Defaults to [Quality::FULL], or [Quality::from_env] for [Picker::from_termios], which lowers
+    /// the quality inside of SSH sessions.
+    pub fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    pub fn quality(&self) -> Quality {
+        self.quality
+    }
+
+    /// Set how kitty states transmit images, see [KittyMedium].
+    pub fn set_kitty_medium(&mut self, medium: KittyMedium) {
+        self.kitty_medium = medium;
+    }
+
+    pub fn kitty_medium(&self) -> KittyMedium {
+        self.kitty_medium
+    }
+
+    /// Use the fastest [KittyMedium] that the terminal can read from, and return it.
+    ///
+    /// Each medium is tried with a kitty query. Terminals that can't read files, for example
+    /// through SSH, or that don't answer, get [KittyMedium::Direct].
+    ///
+    /// This writes and reads from stdin momentarily, like [Picker::from_termios].
+    #[cfg(feature = "rustix")]
+    pub fn query_kitty_medium(&mut self) -> KittyMedium {
+        self.kitty_medium = [KittyMedium::SharedMemory, KittyMedium::TempFile]
+            .into_iter()
+            .find(|medium| query_kitty_medium(*medium).unwrap_or(false))
+            .unwrap_or(KittyMedium::Direct);
+        self.kitty_medium
+    }
+
+    /// The allocator of kitty image ids of this picker, see [KittyIds::take_deletes].
+    pub fn kitty_ids(&self) -> &KittyIds {
+        &self.kitty_ids
+    }
+
+    /// Set how kitty images are placed, see [KittyPlacement].
+    ///
+    /// Defaults to [KittyPlacement::Placeholders], or [KittyPlacement::from_env] for
+    /// [Picker::from_termios].
+    pub fn set_kitty_placement(&mut self, placement: KittyPlacement) {
+        self.kitty_placement = placement;
+    }
+
+    pub fn kitty_placement(&self) -> KittyPlacement {
+        self.kitty_placement
     }
 
-    /// Cycle through available protocols.
+    /// Set where kitty states scale and crop images, see [KittyScaling].
+    pub fn set_kitty_scaling(&mut self, scaling: KittyScaling) {
+        self.kitty_scaling = scaling;
+    }
+
+    pub fn kitty_scaling(&self) -> KittyScaling {
+        self.kitty_scaling
+    }
+
+    /// Set how sixel states encode images, see [SixelOptions].
+    pub fn set_sixel_options(&mut self, options: SixelOptions) {
+        self.sixel_options = options;
+    }
+
+    pub fn sixel_options(&self) -> SixelOptions {
+        self.sixel_options
+    }
+
+    /// Ask the terminal to answer the commands of kitty protocols, see [KittyResponses].
+    pub fn set_kitty_responses(&mut self, responses: Option<KittyResponses>) {
+        self.kitty_responses = responses;
+    }
+
+    /// Have kitty protocols push their commands to `sequences`, to be written after drawing,
+    /// instead of the last cell of the buffer. See [KittySequences].
+    pub fn set_kitty_sequences(&mut self, sequences: Option<KittySequences>) {
+        self.kitty_sequences = sequences;
+    }
+
+    /// Use the [KittyPlacement] for the terminal's reported name and version, and return it.
+    ///
+    /// Terminals that don't report their version keep the current placement.
+    ///
+    /// This writes and reads from stdin momentarily, like [Picker::from_termios].
+    #[cfg(feature = "rustix")]
+    pub fn query_kitty_placement(&mut self) -> KittyPlacement {
+        // The device attributes are queried right after, which every terminal answers.
+        let response = query_terminal(b"\x1b[>q\x1b[c", |buf| {
+            buf.rfind("\x1b[?")
+                .map_or(false, |start| buf[start..].ends_with('c'))
+        });
+        if let Some(placement) = response
+            .ok()
+            .and_then(|response| KittyPlacement::from_xtversion(&response))
+        {
+            self.kitty_placement = placement;
+        }
+        self.kitty_placement
+    }
+
+    /// Totals of the [crate::stats::RenderStats] of all protocols built by this picker.
+    ///
+    /// Fixed protocols only count their encoding, as they are rendered without any state. States
+    /// from a [ProtocolFactory] are not counted at all.
+    pub fn stats(&self) -> TelemetryTotals {
+        self.telemetry.totals()
+    }
+
+    /// Set the totals of [Picker::stats] back to zero.
+    pub fn reset_stats(&self) {
+        self.telemetry.reset();
+    }
+
+    /// Register a custom protocol, returning the [ProtocolType::Custom] to select it with.
+    ///
+    /// Registered protocols are included in [Picker::cycle_protocols], after the built-in ones.
+    /// Fails when 256 protocols have been registered already.
+    pub fn register(&mut self, factory: impl ProtocolFactory + 'static) -> Result<ProtocolType> {
+        let index = u8::try_from(self.factories.len()).map_err(|_| {
+            Error::UnsupportedProtocol(String::from("at most 256 custom protocols"))
+        })?;
+        self.factories.push(Arc::new(factory));
+        Ok(ProtocolType::Custom(index))
+    }
+
+    /// Cycle through available protocols, including registered custom protocols.
     pub fn cycle_protocols(&mut self) -> ProtocolType {
-        self.protocol_type = self.protocol_type.next();
+        self.protocol_type = match self.protocol_type {
+            ProtocolType::Iterm if !self.factories.is_empty() => ProtocolType::Custom(0),
+            ProtocolType::Custom(n) if (n as usize + 1) < self.factories.len() => {
+                ProtocolType::Custom(n + 1)
+            }
+            protocol_type => protocol_type.next(),
+        };
         self.protocol_type
     }
 
+    fn factory(&self, index: u8) -> Option<&Arc<dyn ProtocolFactory>> {
+        self.factories.get(index as usize)
+    }
+
     /// Returns a new *static* protocol for [`crate::FixedImage`] widgets that fits into the given size.
+    ///
+    /// With an [EncodingCache] set, a cached encoding is returned without resizing or encoding.
+    ///
+    /// The image can be a `DynamicImage` or an `Arc<DynamicImage>` shared with other widgets.
     pub fn new_static_fit(
         &mut self,
-        image: DynamicImage,
+        image: impl Into<Arc<DynamicImage>>,
         size: Rect,
         resize: Resize,
     ) -> Result<Box<dyn Protocol>> {
-        let source = ImageSource::new(image, self.font_size);
-        match self.protocol_type {
-            ProtocolType::Halfblocks => Ok(Box::new(FixedHalfblocks::from_source(
+        let source = ImageSource::with_hash(image, self.font_size, self.image_hash);
+        let cache = self.cache.as_ref();
+        let protocol: Result<Box<dyn Protocol>> = match self.protocol_type {
+            ProtocolType::Halfblocks => Ok(Box::new(FixedHalfblocks::from_source_cached(
                 &source,
                 resize,
                 self.background_color,
                 size,
+                cache,
             )?)),
-            #[cfg(feature = "sixel")]
-            ProtocolType::Sixel => Ok(Box::new(FixedSixel::from_source(
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+            ProtocolType::Sixel => Ok(Box::new(FixedSixel::from_source_cached(
                 &source,
                 resize,
                 self.background_color,
                 size,
+                self.quality,
+                self.sixel_options,
+                cache,
             )?)),
-            ProtocolType::Kitty => {
-                self.kitty_counter += 1;
-                Ok(Box::new(FixedKitty::from_source(
+            ProtocolType::Kitty => Ok(Box::new(
+                FixedKitty::from_source_cached(
                     &source,
                     resize,
                     self.background_color,
                     size,
-                    self.kitty_counter,
-                )?))
-            }
-            ProtocolType::Iterm => Ok(Box::new(FixedIterm::from_source(
+                    self.kitty_ids.allocate(),
+                    self.quality,
+                    cache,
+                )?
+                .placement(self.kitty_placement)
+                .responses(self.kitty_responses.clone())
+                .sequences(self.kitty_sequences.clone()),
+            )),
+            ProtocolType::Iterm => Ok(Box::new(FixedIterm::from_source_cached(
                 &source,
                 resize,
                 self.background_color,
                 size,
+                self.quality,
+                cache,
             )?)),
-        }
+            ProtocolType::Custom(index) => match self.factory(index) {
+                Some(factory) => factory.new_static(&source, resize, self.background_color, size),
+                None => Err(unregistered(index)),
+            },
+        };
+        let protocol = protocol?;
+        self.telemetry.record_encode(&protocol.stats());
+        Ok(protocol)
     }
 
     /// Returns a new *state* protocol for [`crate::ResizeImage`].
-    pub fn new_state(&mut self, image: DynamicImage) -> Box<dyn ResizeProtocol> {
-        let source = ImageSource::new(image, self.font_size);
+    ///
+    /// The image can be a `DynamicImage` or an `Arc<DynamicImage>` shared with other widgets.
+    pub fn new_state(&mut self, image: impl Into<Arc<DynamicImage>>) -> Box<dyn ResizeProtocol> {
+        let source = ImageSource::with_hash(image, self.font_size, self.image_hash);
+        self.new_state_from_source(source)
+    }
+
+    /// Convert a *state* protocol to the current [ProtocolType], reusing its [ImageSource].
+    ///
+    /// Switching protocols does not need to keep a copy of the image around, nor hash it again.
+    /// The previous state is cleared (see [ResizeProtocol::clear]) at the first render of the new
+    /// one, which e.g. deletes a kitty image from the terminal. States without a source are
+    /// left as they are.
+    ///
+    /// # Example
+    /// ```rust
+    /// use ratatui_image::picker::{ProtocolType, Picker};
+    ///
+    /// let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+    /// let mut state = picker.new_state(image::DynamicImage::new_rgb8(70, 140));
+    ///
+    /// picker.cycle_protocols();
+    /// picker.switch_state(&mut state);
+    /// ```
+    pub fn switch_state(&mut self, state: &mut Box<dyn ResizeProtocol>) {
+        let source = match state.source() {
+            Some(source) => source.clone(),
+            None => return,
+        };
+        let next = self.new_state_from_source(source);
+        let previous = std::mem::replace(state, Box::new(EmptyState));
+        *state = Box::new(SwitchedState {
+            previous: Some(previous),
+            next,
+        });
+    }
+
+    /// Returns a new *state* protocol for [`crate::ResizeImage`] from an [ImageSource].
+    ///
+    /// Useful with [ImageSource::with_id] to skip hashing the image. The source should have been
+    /// created with [Picker::font_size].
+    pub fn new_state_from_source(&mut self, source: ImageSource) -> Box<dyn ResizeProtocol> {
+        let cache = self.cache.clone();
+        let mut generation = self.generation.clone();
+        generation.update();
+        let telemetry = self.telemetry.clone();
+        let quality = self.quality;
         match self.protocol_type {
-            ProtocolType::Halfblocks => Box::new(HalfblocksState::new(source)),
-            #[cfg(feature = "sixel")]
-            ProtocolType::Sixel => Box::new(SixelState::new(source)),
-            ProtocolType::Kitty => {
-                self.kitty_counter += 1;
-                Box::new(KittyState::new(source, self.kitty_counter))
-            }
-            ProtocolType::Iterm => Box::new(ItermState::new(source)),
+            ProtocolType::Halfblocks => Box::new(
+                HalfblocksState::new(source)
+                    .cache(cache)
+                    .generation(generation)
+                    .telemetry(telemetry),
+            ),
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+            ProtocolType::Sixel => Box::new(
+                SixelState::new(source)
+                    .cache(cache)
+                    .generation(generation)
+                    .telemetry(telemetry)
+                    .quality(quality)
+                    .options(self.sixel_options),
+            ),
+            ProtocolType::Kitty => Box::new(
+                KittyState::new(source, self.kitty_ids.allocate())
+                    .cache(cache)
+                    .generation(generation)
+                    .telemetry(telemetry)
+                    .quality(quality)
+                    .medium(self.kitty_medium)
+                    .placement(self.kitty_placement)
+                    .scaling(self.kitty_scaling)
+                    .responses(self.kitty_responses.clone())
+                    .sequences(self.kitty_sequences.clone()),
+            ),
+            ProtocolType::Iterm => Box::new(
+                ItermState::new(source)
+                    .cache(cache)
+                    .generation(generation)
+                    .telemetry(telemetry)
+                    .quality(quality),
+            ),
+            ProtocolType::Custom(index) => match self.factory(index) {
+                Some(factory) => factory.new_state(source),
+                // Unreachable, as only registered custom protocols can be set.
+                None => Box::new(
+                    HalfblocksState::new(source)
+                        .cache(cache)
+                        .generation(generation)
+                        .telemetry(telemetry),
+                ),
+            },
         }
     }
 
+    /// Returns a new [KittyAnimationState] for [`crate::ResizeImage`], regardless of the
+    /// [ProtocolType], placed with the picker's [KittyPlacement].
+    pub fn new_kitty_animation_state(&mut self, frames: Vec<KittyFrame>) -> KittyAnimationState {
+        let mut generation = self.generation.clone();
+        generation.update();
+        KittyAnimationState::new(frames, self.font_size, self.kitty_ids.allocate())
+            .generation(generation)
+            .telemetry(self.telemetry.clone())
+            .quality(self.quality)
+            .placement(self.kitty_placement)
+            .responses(self.kitty_responses.clone())
+            .sequences(self.kitty_sequences.clone())
+    }
+
     pub fn protocol_type(&self) -> &ProtocolType {
         &self.protocol_type
     }
@@ -183,6 +521,76 @@ impl Picker {
     }
 }
 
+fn unregistered(index: u8) -> Error {
+    Error::UnsupportedProtocol(format!("custom protocol {index} is not registered"))
+}
+
+/// Stands in for a state while it is being switched, without any image.
+#[derive(Clone)]
+struct EmptyState;
+
+impl ResizeProtocol for EmptyState {
+    fn rect(&self) -> Rect {
+        Rect::default()
+    }
+}
+
+/// A state from [Picker::switch_state], that clears the previous state at its first render.
+#[derive(Clone)]
+struct SwitchedState {
+    previous: Option<Box<dyn ResizeProtocol>>,
+    next: Box<dyn ResizeProtocol>,
+}
+
+impl SwitchedState {
+    fn clear_previous(&mut self, buf: &mut Buffer) {
+        if let Some(mut previous) = self.previous.take() {
+            previous.clear(buf);
+        }
+    }
+}
+
+impl ResizeProtocol for SwitchedState {
+    fn rect(&self) -> Rect {
+        self.next.rect()
+    }
+    fn source(&self) -> Option<&ImageSource> {
+        self.next.source()
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        self.next.into_source()
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        self.next.needs_resize(resize, area)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
+        self.next.resize_encode(resize, background_color, area)
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.clear_previous(buf);
+        self.next.render_current(area, buf)
+    }
+    fn reset(&mut self) {
+        self.next.reset()
+    }
+    fn clear(&mut self, buf: &mut Buffer) {
+        self.clear_previous(buf);
+        self.next.clear(buf)
+    }
+    fn stats(&self) -> RenderStats {
+        self.next.stats()
+    }
+    fn last_error(&self) -> Option<&Error> {
+        self.next.last_error()
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        self.next.set_layer(layer)
+    }
+    fn set_pan(&mut self, pan: (u32, u32)) {
+        self.next.set_pan(pan)
+    }
+}
+
 #[cfg(feature = "rustix")]
 pub fn font_size(winsize: Winsize) -> Result<FontSize> {
     let Winsize {
@@ -192,7 +600,7 @@ pub fn font_size(winsize: Winsize) -> Result<FontSize> {
         ws_row: rows,
     } = winsize;
     if x == 0 || y == 0 || cols == 0 || rows == 0 {
-        return Err(String::from("font_size zero value").into());
+        return Err(Error::ZeroFontSize);
     }
     Ok((x / cols, y / rows))
 }
@@ -202,12 +610,12 @@ pub fn font_size(winsize: Winsize) -> Result<FontSize> {
 fn guess_protocol() -> ProtocolType {
     if let Ok(term) = std::env::var("TERM") {
         match term.as_str() {
-            #[cfg(all(feature = "sixel", feature = "rustix"))]
+            #[cfg(all(any(feature = "sixel", feature = "sixel-native"), feature = "rustix"))]
             "mlterm" | "yaft-256color" => {
                 return ProtocolType::Sixel;
             }
             term => {
-                #[cfg(all(feature = "sixel", feature = "rustix"))]
+                #[cfg(all(any(feature = "sixel", feature = "sixel-native"), feature = "rustix"))]
                 match check_device_attrs() {
                     Ok(t) => return t,
                     Err(err) => eprintln!("{err}"),
@@ -215,7 +623,7 @@ fn guess_protocol() -> ProtocolType {
                 if term.contains("kitty") {
                     return ProtocolType::Kitty;
                 }
-                #[cfg(all(feature = "sixel", feature = "rustix"))]
+                #[cfg(all(any(feature = "sixel", feature = "sixel-native"), feature = "rustix"))]
                 if let Ok(term_program) = std::env::var("TERM_PROGRAM") {
                     if term_program == "MacTerm" {
                         return ProtocolType::Sixel;
@@ -227,7 +635,7 @@ fn guess_protocol() -> ProtocolType {
     ProtocolType::Halfblocks
 }
 
-#[cfg(all(feature = "sixel", feature = "rustix"))]
+#[cfg(all(any(feature = "sixel", feature = "sixel-native"), feature = "rustix"))]
 /// Check if Sixel is within the terminal's attributes
 /// see https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Sixel-Graphics
 /// and https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h4-Functions-using-CSI-_-ordered-by-the-final-character-lparen-s-rparen:CSI-Ps-c.1CA3
@@ -240,51 +648,100 @@ fn guess_protocol() -> ProtocolType {
 /// * kitty (negative)
 /// * wezterm
 fn check_device_attrs() -> Result<ProtocolType> {
+    let buf = query_terminal(b"\x1b[c", |buf| buf.ends_with('c'))?;
+
+    if buf.contains(";4;") || buf.contains("?4;") || buf.contains(";4c") || buf.contains("?4c") {
+        Ok(ProtocolType::Sixel)
+    } else {
+        Err(Error::UnsupportedProtocol(format!(
+            "CSI sixel support not detected: ^[{}",
+            if buf.len() > 1 {
+                &buf[1..]
+            } else {
+                "(nothing)"
+            }
+        )))
+    }
+}
+
+/// Check if the terminal can read a kitty image from `medium`.
+///
+/// The device attributes are queried right after, which every terminal answers, so that
+/// terminals without kitty graphics don't have to time out.
+#[cfg(feature = "rustix")]
+fn query_kitty_medium(medium: KittyMedium) -> Result<bool> {
+    // The file must exist until the terminal has answered.
+    let (query, _file) = medium.query()?;
+    let query = format!("{query}\x1b[c");
+    let buf = query_terminal(query.as_bytes(), |buf| {
+        buf.rfind("\x1b[?")
+            .map_or(false, |start| buf[start..].ends_with('c'))
+    })?;
+    let id = crate::protocol::kitty::QUERY_ID;
+    Ok(buf.contains(&format!("\x1b_Gi={id};OK\x1b\\")))
+}
+
+/// Write `query` to stdout, and read the response from stdin until `done`.
+#[cfg(feature = "rustix")]
+fn query_terminal(query: &[u8], done: impl Fn(&str) -> bool) -> Result<String> {
     let stdin = rustix::stdio::stdin();
     let termios_original = rustix::termios::tcgetattr(stdin)?;
     let mut termios = termios_original.clone();
     // Disable canonical mode to read without waiting for Enter, disable echoing
     termios.local_modes &= !LocalModes::ICANON;
     termios.local_modes &= !LocalModes::ECHO;
+    // Return from read() after one second without any input, instead of blocking forever
+    termios.special_codes[SpecialCodeIndex::VMIN] = 0;
+    termios.special_codes[SpecialCodeIndex::VTIME] = 10;
     rustix::termios::tcsetattr(stdin, OptionalActions::Drain, &termios)?;
 
-    rustix::io::write(rustix::stdio::stdout(), b"\x1b[c")?;
+    let result = read_response(stdin, query, done);
+    // Reset to previous attrs
+    rustix::termios::tcsetattr(stdin, OptionalActions::Now, &termios_original)?;
+    result
+}
+
+#[cfg(feature = "rustix")]
+fn read_response(
+    stdin: rustix::fd::BorrowedFd,
+    query: &[u8],
+    done: impl Fn(&str) -> bool,
+) -> Result<String> {
+    rustix::io::write(rustix::stdio::stdout(), query)?;
 
     let mut buf = String::new();
     loop {
         let mut charbuf: [u8; 1] = [0; 1];
-        rustix::io::read(stdin, &mut charbuf)?;
+        if rustix::io::read(stdin, &mut charbuf)? == 0 {
+            return Err(Error::Timeout);
+        }
         if charbuf[0] == 0 {
             continue;
         }
         buf.push(char::from(charbuf[0]));
-        if charbuf[0] == b'c' {
+        if done(&buf) {
             break;
         }
     }
-    // Reset to previous attrs
-    rustix::termios::tcsetattr(stdin, OptionalActions::Now, &termios_original)?;
-
-    if buf.contains(";4;") || buf.contains("?4;") || buf.contains(";4c") || buf.contains("?4c") {
-        Ok(ProtocolType::Sixel)
-    } else {
-        Err(format!(
-            "CSI sixel support not detected: ^[{}",
-            if buf.len() > 1 {
-                &buf[1..]
-            } else {
-                "(nothing)"
-            }
-        )
-        .into())
-    }
+    Ok(buf)
 }
 
-#[cfg(all(test, feature = "rustix", feature = "sixel"))]
+#[cfg(all(
+    test,
+    feature = "rustix",
+    any(feature = "sixel", feature = "sixel-native")
+))]
 mod tests {
     use std::assert_eq;
 
-    use crate::picker::{font_size, Picker, ProtocolType};
+    use image::DynamicImage;
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use crate::{
+        picker::{font_size, Picker, ProtocolType},
+        protocol::kitty::KittyPlacement,
+        Resize,
+    };
     use rustix::termios::Winsize;
 
     #[test]
@@ -308,9 +765,28 @@ mod tests {
     #[test]
     fn test_cycle_protocol() {
         let mut picker = Picker::new((1, 1), ProtocolType::Halfblocks, None).unwrap();
-        #[cfg(feature = "sixel")]
+        #[cfg(any(feature = "sixel", feature = "sixel-native"))]
         assert_eq!(picker.cycle_protocols(), ProtocolType::Sixel);
         assert_eq!(picker.cycle_protocols(), ProtocolType::Kitty);
+        assert_eq!(picker.cycle_protocols(), ProtocolType::Iterm);
         assert_eq!(picker.cycle_protocols(), ProtocolType::Halfblocks);
     }
+
+    #[test]
+    fn switch_state_deletes_kitty_image() {
+        let mut picker = Picker::new((1, 2), ProtocolType::Kitty, None).unwrap();
+        picker.set_kitty_placement(KittyPlacement::Placeholders);
+        let mut state = picker.new_state(DynamicImage::new_rgb8(4, 8));
+        let area = Rect::new(0, 0, 4, 4);
+        // The delete goes into the bottom-right cell of the buffer, outside of the image.
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+        state.render(&Resize::Fit, None, area, &mut buf);
+
+        picker.set(ProtocolType::Halfblocks).unwrap();
+        picker.switch_state(&mut state);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+        state.render(&Resize::Fit, None, area, &mut buf);
+        assert!(buf.get(7, 7).symbol.starts_with("\x1b_Gq=2,a=d,d=I,i="));
+        assert!(buf.get(0, 0).symbol.starts_with('▀'));
+    }
 }
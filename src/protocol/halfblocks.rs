@@ -4,17 +4,23 @@
 use image::{imageops::FilterType, DynamicImage, Rgb};
 use ratatui::{buffer::Buffer, layout::Rect, style::Color};
 
-use super::{Protocol, ResizeProtocol};
-use crate::{ImageSource, Resize, Result};
+use super::{clear_area, covered_area, has_text, Generation, Protocol, ResizeProtocol};
+use crate::{
+    cache::{self, Encoded, EncodingCache, EncodingKey},
+    picker::ProtocolType,
+    stats::{self, RenderStats, Telemetry},
+    ImageSource, Layer, Resize, Result,
+};
 
 // Fixed Halfblocks protocol
 #[derive(Clone, Default)]
 pub struct FixedHalfblocks {
     data: Vec<HalfBlock>,
     rect: Rect,
+    stats: RenderStats,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 struct HalfBlock {
     upper: Color,
     lower: Color,
@@ -32,14 +38,45 @@ impl FixedHalfblocks {
         background_color: Option<Rgb<u8>>,
         area: Rect,
     ) -> Result<Self> {
-        let (image, desired) = resize
-            .resize(source, Rect::default(), area, background_color, false)
-            .unwrap_or_else(|| (source.image.clone(), source.desired));
-        let data = encode(&image, desired);
-        Ok(Self {
-            data,
-            rect: desired,
-        })
+        Self::from_source_cached(source, resize, background_color, area, None)
+    }
+
+    pub(crate) fn from_source_cached(
+        source: &ImageSource,
+        resize: Resize,
+        background_color: Option<Rgb<u8>>,
+        area: Rect,
+        cache: Option<&EncodingCache>,
+    ) -> Result<Self> {
+        let rect = resize
+            .needs_resize(source, Rect::default(), area, false)
+            .unwrap_or(source.desired);
+        let key = EncodingKey::new(
+            source,
+            rect,
+            &resize,
+            background_color,
+            ProtocolType::Halfblocks,
+        );
+        let mut stats = RenderStats {
+            transmitted: true,
+            ..RenderStats::default()
+        };
+        let mut fixed = cache::get_or_encode(cache, key, || {
+            let (image, desired) = stats::timed(&mut stats.resize_time, || {
+                resize
+                    .resize(source, Rect::default(), area, background_color, false)
+                    .unwrap_or_else(|| ((*source.image).clone(), source.desired))
+            });
+            let data = stats::timed(&mut stats.encode_time, || encode(&image, desired));
+            Ok(Self {
+                data,
+                rect: desired,
+                stats: RenderStats::default(),
+            })
+        })?;
+        fixed.stats = stats;
+        Ok(fixed)
     }
 }
 
@@ -73,6 +110,20 @@ fn encode(img: &DynamicImage, rect: Rect) -> Vec<HalfBlock> {
 
 impl Protocol for FixedHalfblocks {
     fn render(&self, area: Rect, buf: &mut Buffer) {
+        self.render_layer(area, buf, Layer::default());
+    }
+    fn render_layer(&self, area: Rect, buf: &mut Buffer, layer: Layer) {
+        self.render_cells(area, buf, layer, false);
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+}
+
+impl FixedHalfblocks {
+    /// Render with `▄` and swapped colors if `flip`, which looks the same but differs from the
+    /// last frame, so that ratatui redraws the cells.
+    fn render_cells(&self, area: Rect, buf: &mut Buffer, layer: Layer, flip: bool) {
         for (i, hb) in self.data.iter().enumerate() {
             let x = i as u16 % self.rect.width;
             let y = i as u16 / self.rect.width;
@@ -80,19 +131,54 @@ impl Protocol for FixedHalfblocks {
                 continue;
             }
 
-            buf.get_mut(area.x + x, area.y + y)
-                .set_fg(hb.upper)
-                .set_bg(hb.lower)
-                .set_char('▀');
+            let cell = buf.get_mut(area.x + x, area.y + y);
+            if layer.is_below_text() && has_text(cell) {
+                // Keep the text, with both halves as its background.
+                cell.set_bg(blend(hb.upper, hb.lower));
+                continue;
+            }
+            if flip {
+                cell.set_fg(hb.lower).set_bg(hb.upper).set_char('▄');
+            } else {
+                cell.set_fg(hb.upper).set_bg(hb.lower).set_char('▀');
+            }
         }
     }
 }
 
+fn blend(upper: Color, lower: Color) -> Color {
+    match (upper, lower) {
+        (Color::Rgb(r1, g1, b1), Color::Rgb(r2, g2, b2)) => Color::Rgb(
+            ((r1 as u16 + r2 as u16) / 2) as u8,
+            ((g1 as u16 + g2 as u16) / 2) as u8,
+            ((b1 as u16 + b2 as u16) / 2) as u8,
+        ),
+        _ => lower,
+    }
+}
+
+impl Encoded for FixedHalfblocks {
+    fn byte_size(&self) -> usize {
+        self.data.len() * std::mem::size_of::<HalfBlock>()
+    }
+}
+
 #[derive(Clone)]
 pub struct HalfblocksState {
     source: ImageSource,
     current: FixedHalfblocks,
     hash: u64,
+    cache: Option<EncodingCache>,
+    generation: Generation,
+    /// The area covered at the last render
+    area: Rect,
+    telemetry: Telemetry,
+    stats: RenderStats,
+    /// Whether `current` has not been rendered yet
+    fresh: bool,
+    /// Whether the cells are flipped, toggled on every new generation
+    flip: bool,
+    layer: Layer,
 }
 
 impl HalfblocksState {
@@ -101,38 +187,106 @@ impl HalfblocksState {
             source,
             current: FixedHalfblocks::default(),
             hash: u64::default(),
+            cache: None,
+            generation: Generation::default(),
+            area: Rect::default(),
+            telemetry: Telemetry::default(),
+            stats: RenderStats::default(),
+            fresh: false,
+            flip: false,
+            layer: Layer::default(),
         }
     }
+
+    /// Share encodings through an [EncodingCache].
+    pub fn cache(mut self, cache: Option<EncodingCache>) -> HalfblocksState {
+        self.cache = cache;
+        self
+    }
+
+    /// Invalidate together with all other states holding a clone of `generation`.
+    pub fn generation(mut self, generation: Generation) -> HalfblocksState {
+        self.generation = generation;
+        self
+    }
+
+    /// Add [RenderStats] up in a shared [Telemetry].
+    pub fn telemetry(mut self, telemetry: Telemetry) -> HalfblocksState {
+        self.telemetry = telemetry;
+        self
+    }
 }
 
 impl ResizeProtocol for HalfblocksState {
     fn rect(&self) -> Rect {
         self.current.rect
     }
-    fn render(
-        &mut self,
-        resize: &Resize,
-        background_color: Option<Rgb<u8>>,
-        area: Rect,
-        buf: &mut Buffer,
-    ) {
+    fn source(&self) -> Option<&ImageSource> {
+        Some(&self.source)
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        Some(self.source)
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        let force = self.source.hash != self.hash || self.generation.is_stale();
+        resize.needs_resize(&self.source, self.current.rect, area, force)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
         if area.width == 0 || area.height == 0 {
             return;
         }
 
-        let force = self.source.hash != self.hash;
-        if let Some((img, rect)) = resize.resize(
-            &self.source,
-            self.current.rect,
-            area,
-            background_color,
-            force,
-        ) {
-            let data = encode(&img, rect);
-            let current = FixedHalfblocks { data, rect };
-            self.current = current;
-            self.hash = self.source.hash;
+        let stale = self.generation.update();
+        self.flip ^= stale;
+        let force = stale || self.source.hash != self.hash;
+        if let Some(rect) = resize.needs_resize(&self.source, self.current.rect, area, force) {
+            let key = EncodingKey::new(
+                &self.source,
+                rect,
+                resize,
+                background_color,
+                ProtocolType::Halfblocks,
+            );
+            let mut stats = RenderStats::default();
+            let encoded = cache::get_or_encode(self.cache.as_ref(), key, || {
+                let img = stats::timed(&mut stats.resize_time, || {
+                    resize.resize_to(&self.source, rect, background_color)
+                });
+                let data = stats::timed(&mut stats.encode_time, || encode(&img, rect));
+                Ok(FixedHalfblocks {
+                    data,
+                    rect,
+                    stats: RenderStats::default(),
+                })
+            });
+            if let Ok(current) = encoded {
+                // The same cells are not drawn again, unless flipped.
+                self.fresh |= stale || current.data != self.current.data;
+                self.current = current;
+                self.hash = self.source.hash;
+                self.stats = stats;
+                self.telemetry.record_encode(&stats);
+            }
         }
-        FixedHalfblocks::render(&self.current, area, buf);
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.area = covered_area(self.current.rect, area);
+        self.current.render_cells(area, buf, self.layer, self.flip);
+        self.stats.transmitted = std::mem::take(&mut self.fresh);
+        self.telemetry.record_render(&self.stats);
+    }
+    fn reset(&mut self) {
+        self.current = FixedHalfblocks::default();
+        self.hash = u64::default();
+    }
+    fn clear(&mut self, buf: &mut Buffer) {
+        clear_area(self.area, buf);
+        self.area = Rect::default();
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        self.layer = layer;
     }
 }
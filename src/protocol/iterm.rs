@@ -5,18 +5,28 @@
 //! [`sixel-bytes`]: https://github.com/benjajaja/sixel-bytes
 //! [supports]: https://arewesixelyet.com
 //! [Iterm]: https://en.wikipedia.org/wiki/Iterm
-use image::{DynamicImage, Rgb};
+use image::{DynamicImage, ImageOutputFormat, Rgb, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
-use std::{cmp::min, io::Cursor};
+use std::{borrow::Cow, cmp::min, io::Cursor, sync::Arc};
 
-use super::{Protocol, ResizeProtocol};
-use crate::{ImageSource, Resize, Result};
+use super::{
+    clear_area, covered_area, has_text, set_first_cell, Generation, Protocol, ResizeProtocol,
+    REDRAW,
+};
+use crate::{
+    cache::{self, Encoded, EncodingCache, EncodingKey},
+    picker::ProtocolType,
+    quality::{self, Quality},
+    stats::{self, RenderStats, Telemetry},
+    Error, FontSize, ImageSource, Layer, Resize, Result,
+};
 
 // Fixed sixel protocol
 #[derive(Clone, Default)]
 pub struct FixedIterm {
     pub data: String,
     pub rect: Rect,
+    stats: RenderStats,
 }
 
 impl FixedIterm {
@@ -26,42 +36,153 @@ impl FixedIterm {
         background_color: Option<Rgb<u8>>,
         area: Rect,
     ) -> Result<Self> {
-        let (img, rect) = resize
-            .resize(source, Rect::default(), area, background_color, false)
-            .unwrap_or_else(|| (source.image.clone(), source.desired));
+        Self::from_source_cached(
+            source,
+            resize,
+            background_color,
+            area,
+            Quality::default(),
+            None,
+        )
+    }
+
+    pub(crate) fn from_source_cached(
+        source: &ImageSource,
+        resize: Resize,
+        background_color: Option<Rgb<u8>>,
+        area: Rect,
+        quality: Quality,
+        cache: Option<&EncodingCache>,
+    ) -> Result<Self> {
+        let rect = resize
+            .needs_resize(source, Rect::default(), area, false)
+            .unwrap_or(source.desired);
+        let key = EncodingKey::new(source, rect, &resize, background_color, ProtocolType::Iterm)
+            .quality(quality);
+        let mut stats = RenderStats {
+            transmitted: true,
+            ..RenderStats::default()
+        };
+        let mut fixed = cache::get_or_encode(cache, key, || {
+            let (img, rect) = stats::timed(&mut stats.resize_time, || {
+                resize
+                    .resize(source, Rect::default(), area, background_color, false)
+                    .unwrap_or_else(|| ((*source.image).clone(), source.desired))
+            });
 
-        let data = encode(img,rect.width.into(),rect.height.into())?;
-        Ok(Self { data, rect })
+            let data = stats::timed(&mut stats.encode_time, || {
+                encode_with_quality(&img, rect, source.font_size, &quality, background_color)
+            })?;
+            Ok(Self {
+                data,
+                rect,
+                stats: RenderStats::default(),
+            })
+        })?;
+        stats.bytes = fixed.data.len();
+        fixed.stats = stats;
+        Ok(fixed)
     }
 }
 
-// TODO: change E to sixel_rs::status::Error and map when calling
-pub fn encode(img: DynamicImage,width:u64,height:u64) -> Result<String> {
+pub fn encode(img: DynamicImage, width: u64, height: u64) -> Result<String> {
+    encode_format(&img, width, height, None)
+}
+
+/// Encode an image resized for `rect` with the pixels per cell, format and frame budget of
+/// `quality`. The terminal scales the image up to the cells.
+///
+/// JPEG has no alpha channel, so transparent pixels are blended onto `background_color`, or black.
+pub fn encode_with_quality(
+    img: &DynamicImage,
+    rect: Rect,
+    font_size: FontSize,
+    quality: &Quality,
+    background_color: Option<Rgb<u8>>,
+) -> Result<String> {
+    let img = match quality.iterm_jpeg {
+        Some(_) => Cow::Owned(flatten(img, background_color.unwrap_or(Rgb([0, 0, 0])))),
+        None => Cow::Borrowed(img),
+    };
+    quality.encode_cells_within_budget(font_size, |cell_pixels| {
+        let img = quality::downscale(&img, rect, cell_pixels);
+        encode_format(
+            &img,
+            rect.width.into(),
+            rect.height.into(),
+            quality.iterm_jpeg,
+        )
+    })
+}
+
+/// Blend the pixels of `img` onto `background`.
+fn flatten(img: &DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+    let mut rgba = img.to_rgba8();
+    for Rgba([r, g, b, a]) in rgba.pixels_mut() {
+        let alpha = *a as u16;
+        for (channel, bg) in [r, g, b].into_iter().zip(background.0) {
+            *channel = ((*channel as u16 * alpha + bg as u16 * (255 - alpha)) / 255) as u8;
+        }
+        *a = 255;
+    }
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+}
+
+/// Encode as PNG, or as JPEG with the given quality.
+fn encode_format(img: &DynamicImage, width: u64, height: u64, jpeg: Option<u8>) -> Result<String> {
     let mut buffer: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-    let _ = img.write_to(&mut buffer, image::ImageFormat::Png)?;
+    match jpeg {
+        // JPEG has no alpha channel, see [flatten].
+        Some(quality) => DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut buffer, ImageOutputFormat::Jpeg(quality))?,
+        None => img.write_to(&mut buffer, ImageOutputFormat::Png)?,
+    }
     let builder = iterm2img::from_bytes(buffer.into_inner()).inline(true);
-    let data = builder.width(width).height(height).preserve_aspect_ratio(false).build();
-    return Ok(data);
+    let data = builder
+        .width(width)
+        .height(height)
+        .preserve_aspect_ratio(false)
+        .build();
+    Ok(data)
 }
 
 impl Protocol for FixedIterm {
     fn render(&self, area: Rect, buf: &mut Buffer) {
-        render(self.rect, &self.data, area, buf, false)
+        self.render_layer(area, buf, Layer::default());
+    }
+    fn render_layer(&self, area: Rect, buf: &mut Buffer, layer: Layer) {
+        render(self.rect, &self.data, area, buf, false, layer)
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
     }
 }
 
-fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool) {
+fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool, layer: Layer) {
+    let render_area = match render_area(rect, area, overdraw) {
+        None => return,
+        Some(r) => r,
+    };
 
-    buf.get_mut(area.left(), area.top())
-        .set_symbol(data);
+    let cell = buf.get_mut(render_area.left(), render_area.top());
+    set_first_cell(cell, data, layer);
 
     // Skip entire area
-    for y in area.top()..area.bottom() {
-        for x in area.left()..area.right() {
-            buf.get_mut(x, y).set_skip(true);
+    for y in render_area.top()..render_area.bottom() {
+        for x in render_area.left()..render_area.right() {
+            let cell = buf.get_mut(x, y);
+            // Let the terminal draw text over the image.
+            if layer.is_below_text() && has_text(cell) {
+                continue;
+            }
+            cell.set_skip(true);
         }
     }
-    buf.get_mut(area.left(), area.top()).set_skip(false);
+    buf.get_mut(render_area.left(), render_area.top())
+        .set_skip(false);
 }
 
 fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
@@ -80,11 +201,40 @@ fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
     Some(Rect::new(area.x, area.y, rect.width, rect.height))
 }
 
+impl Encoded for FixedIterm {
+    fn byte_size(&self) -> usize {
+        self.data.len()
+    }
+    fn to_disk(&self) -> Option<&str> {
+        Some(&self.data)
+    }
+    fn from_disk(data: String, key: &EncodingKey) -> Option<Self> {
+        Some(FixedIterm {
+            data,
+            rect: key.rect,
+            stats: RenderStats::default(),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct ItermState {
     source: ImageSource,
     current: FixedIterm,
     hash: u64,
+    cache: Option<EncodingCache>,
+    generation: Generation,
+    /// The area covered at the last render
+    area: Rect,
+    telemetry: Telemetry,
+    stats: RenderStats,
+    /// Whether `current` has not been rendered yet
+    fresh: bool,
+    /// Whether `current` is prefixed with [REDRAW], toggled on every new generation
+    redraw: bool,
+    layer: Layer,
+    quality: Quality,
+    error: Option<Arc<Error>>,
 }
 
 impl ItermState {
@@ -93,45 +243,145 @@ impl ItermState {
             source,
             current: FixedIterm::default(),
             hash: u64::default(),
+            cache: None,
+            generation: Generation::default(),
+            area: Rect::default(),
+            telemetry: Telemetry::default(),
+            stats: RenderStats::default(),
+            fresh: false,
+            redraw: false,
+            layer: Layer::default(),
+            quality: Quality::default(),
+            error: None,
         }
     }
+
+    /// Share encodings through an [EncodingCache].
+    pub fn cache(mut self, cache: Option<EncodingCache>) -> ItermState {
+        self.cache = cache;
+        self
+    }
+
+    /// Invalidate together with all other states holding a clone of `generation`.
+    pub fn generation(mut self, generation: Generation) -> ItermState {
+        self.generation = generation;
+        self
+    }
+
+    /// Add [RenderStats] up in a shared [Telemetry].
+    pub fn telemetry(mut self, telemetry: Telemetry) -> ItermState {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Encode with the pixels per cell, format and budget of `quality`.
+    pub fn quality(mut self, quality: Quality) -> ItermState {
+        self.quality = quality;
+        self
+    }
 }
 
 impl ResizeProtocol for ItermState {
     fn rect(&self) -> Rect {
         self.current.rect
     }
-    fn render(
-        &mut self,
-        resize: &Resize,
-        background_color: Option<Rgb<u8>>,
-        area: Rect,
-        buf: &mut Buffer,
-    ) {
+    fn source(&self) -> Option<&ImageSource> {
+        Some(&self.source)
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        Some(self.source)
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        let force = self.source.hash != self.hash || self.generation.is_stale();
+        resize.needs_resize(&self.source, self.current.rect, area, force)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
         if area.width == 0 || area.height == 0 {
             return;
         }
 
-        let force = self.source.hash != self.hash;
-        if let Some((img, rect)) = resize.resize(
-            &self.source,
-            self.current.rect,
-            area,
-            background_color,
-            force,
-        ) {
-            match encode(img,rect.width.into(),rect.height.into()) {
-                Ok(data) => {
-                    let current = FixedIterm { data, rect };
+        let stale = self.generation.update();
+        self.redraw ^= stale;
+        let force = stale || self.source.hash != self.hash;
+        if let Some(rect) = resize.needs_resize(&self.source, self.current.rect, area, force) {
+            let key = EncodingKey::new(
+                &self.source,
+                rect,
+                resize,
+                background_color,
+                ProtocolType::Iterm,
+            )
+            .quality(self.quality);
+            let mut stats = RenderStats::default();
+            let encoded = cache::get_or_encode(self.cache.as_ref(), key, || {
+                let img = stats::timed(&mut stats.resize_time, || {
+                    resize.resize_to(&self.source, rect, background_color)
+                });
+                let data = stats::timed(&mut stats.encode_time, || {
+                    encode_with_quality(
+                        &img,
+                        rect,
+                        self.source.font_size,
+                        &self.quality,
+                        background_color,
+                    )
+                })?;
+                Ok(FixedIterm {
+                    data,
+                    rect,
+                    stats: RenderStats::default(),
+                })
+            });
+            match encoded {
+                Ok(mut current) => {
+                    if self.redraw {
+                        current.data.insert_str(0, REDRAW);
+                    }
+                    // The same data in the same cell is not drawn again.
+                    self.fresh |= current.data != self.current.data;
                     self.current = current;
                     self.hash = self.source.hash;
+                    self.stats = stats;
+                    self.telemetry.record_encode(&stats);
+                    self.error = None;
                 }
-                Err(_err) => {
-                    // TODO: save err in struct and expose in trait?
-                }
+                Err(err) => self.error = Some(Arc::new(err)),
             }
         }
-
-        render(self.current.rect, &self.current.data, area, buf, true);
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.area = covered_area(self.current.rect, area);
+        render(
+            self.current.rect,
+            &self.current.data,
+            area,
+            buf,
+            true,
+            self.layer,
+        );
+        self.stats.transmitted = std::mem::take(&mut self.fresh);
+        self.stats.bytes = if self.stats.transmitted {
+            self.current.data.len()
+        } else {
+            0
+        };
+        self.telemetry.record_render(&self.stats);
+    }
+    fn reset(&mut self) {
+        self.current = FixedIterm::default();
+        self.hash = u64::default();
+    }
+    fn clear(&mut self, buf: &mut Buffer) {
+        clear_area(self.area, buf);
+        self.area = Rect::default();
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+    fn last_error(&self) -> Option<&Error> {
+        self.error.as_deref()
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        self.layer = layer;
     }
 }
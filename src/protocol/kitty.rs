@@ -1,20 +1,601 @@
 /// https://sw.kovidgoyal.net/kitty/graphics-protocol/#unicode-placeholders
-use std::format;
+use std::{
+    collections::VecDeque,
+    format,
+    io::{self, Cursor, Write},
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use base64::{engine::general_purpose, Engine};
-use image::{DynamicImage, Rgb};
-use ratatui::{buffer::Buffer, layout::Rect};
+use flate2::{write::ZlibEncoder, Compression};
+use image::{DynamicImage, ImageOutputFormat, Rgb};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style},
+};
 
-use crate::{ImageSource, Resize, Result};
+use crate::{
+    cache::{self, Encoded, EncodingCache, EncodingKey},
+    picker::ProtocolType,
+    quality::{self, KittyFormat, Quality},
+    stats::{self, RenderStats, Telemetry},
+    Error, FontSize, ImageSource, Layer, Resize, Result,
+};
 
-use super::{Protocol, ResizeProtocol};
+use super::{clear_area, covered_area, has_text, Generation, ImageHash, Protocol, ResizeProtocol};
+
+/// A kitty image id, together with the id of its placement.
+///
+/// Image ids are 32 bits. The lower 24 bits are sent as the truecolor foreground color of the
+/// unicode placeholders, and the high byte as a third diacritic. The placement id is sent as the
+/// underline color, so it has 24 bits. Images larger than 297 cells in either direction are placed
+/// in tiles, with the following placement ids.
+///
+/// An id from [KittyIds::allocate] is given back to the allocator when its last clone is dropped,
+/// once its image has been deleted from the terminal, see [KittyIds::take_deletes].
+#[derive(Clone, Debug)]
+pub struct KittyId {
+    id: u32,
+    placement: u32,
+    // Keeps the id allocated while held.
+    _lease: Option<Arc<Lease>>,
+}
+
+impl KittyId {
+    /// An image id that is managed by the application.
+    ///
+    /// Unlike ids from [KittyIds::allocate], the image is not deleted from the terminal when the
+    /// id is dropped, see [ResizeProtocol::clear].
+    ///
+    /// # Panics
+    ///
+    /// If `id` is zero, which is not a valid kitty image id.
+    pub fn new(id: u32) -> KittyId {
+        assert!(id != 0, "kitty image ids must not be zero");
+        KittyId {
+            id,
+            placement: 1,
+            _lease: None,
+        }
+    }
+
+    /// Set the placement id, 1 by default.
+    ///
+    /// # Panics
+    ///
+    /// If `placement` is zero or does not fit in 24 bits, as it is sent as the underline color.
+    pub fn placement(mut self, placement: u32) -> KittyId {
+        assert!(
+            (1..=0xFF_FFFF).contains(&placement),
+            "kitty placement ids must be between 1 and 0xFFFFFF"
+        );
+        self.placement = placement;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn placement_id(&self) -> u32 {
+        self.placement
+    }
+
+    /// [KittyIds::take_deletes] of the allocator that this id comes from.
+    fn take_deletes(&self) -> String {
+        self._lease
+            .as_ref()
+            .and_then(|lease| lease.pool.upgrade())
+            .map_or_else(String::new, |pool| lock(&pool).take_deletes())
+    }
+}
+
+impl Default for KittyId {
+    fn default() -> KittyId {
+        KittyId::new(1)
+    }
+}
+
+impl From<NonZeroU32> for KittyId {
+    fn from(id: NonZeroU32) -> KittyId {
+        KittyId::new(id.get())
+    }
+}
+
+/// Allocator of kitty image ids, that reuses the ids of dropped images.
+///
+/// Cloning is cheap and clones share the same ids.
+#[derive(Clone, Debug, Default)]
+pub struct KittyIds {
+    pool: Arc<Mutex<IdPool>>,
+}
+
+#[derive(Debug, Default)]
+struct IdPool {
+    last: u32,
+    free: Vec<u32>,
+    /// Ids that have been dropped, whose images may still be in the terminal's memory.
+    dropped: Vec<u32>,
+}
+
+impl IdPool {
+    fn take_deletes(&mut self) -> String {
+        let mut seq = String::new();
+        for id in self.dropped.drain(..) {
+            seq += &format!("\x1b_Gq=2,a=d,d=I,i={id}\x1b\\");
+            self.free.push(id);
+        }
+        seq
+    }
+}
+
+/// Gives the id back to the pool when dropped, to be deleted before it is reused.
+#[derive(Debug)]
+struct Lease {
+    id: u32,
+    pool: Weak<Mutex<IdPool>>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            lock(&pool).dropped.push(self.id);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    // A panic while holding the lock can't leave the id pool or responses in an invalid state.
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl KittyIds {
+    pub fn new() -> KittyIds {
+        KittyIds::default()
+    }
+
+    /// Take the delete commands for the images of dropped ids, which can be allocated again
+    /// afterwards.
+    ///
+    /// Kitty states and fixed kitty protocols emit these when rendering. Apps that stop rendering
+    /// kitty images, e.g. after switching to another protocol, can write them out after a draw.
+    /// Ids that outlive every clone of the allocator are not deleted.
+    pub fn take_deletes(&self) -> String {
+        lock(&self.pool).take_deletes()
+    }
+
+    /// Allocate an id, preferring ids that have been freed.
+    pub fn allocate(&self) -> KittyId {
+        let mut pool = lock(&self.pool);
+        let id = pool.free.pop().unwrap_or_else(|| {
+            // Zero is not a valid id.
+            pool.last = pool.last.wrapping_add(1).max(1);
+            pool.last
+        });
+        KittyId {
+            id,
+            placement: 1,
+            _lease: Some(Arc::new(Lease {
+                id,
+                pool: Arc::downgrade(&self.pool),
+            })),
+        }
+    }
+}
+
+/// Responses of the terminal to kitty commands, that apps feed from their input stream.
+///
+/// Kitty commands are sent quietly, unless states have [KittyState::responses]. Then the terminal
+/// answers every command with `\x1b_Gi=<id>;OK\x1b\\` or an error, which apps should pass to
+/// [KittyResponses::feed] as they read them from the input stream. States pick the responses for
+/// their image id up when rendering, and report errors with [ResizeProtocol::last_error], or
+/// [Protocol::last_error] for [FixedKitty]. Only the last response of the latest 256 ids is kept.
+///
+/// Cloning is cheap and clones share the same responses.
+#[derive(Clone, Debug, Default)]
+pub struct KittyResponses {
+    /// The last response of every image id, oldest first.
+    shared: Arc<Mutex<VecDeque<(u32, KittyResponse)>>>,
+}
+
+type KittyResponse = std::result::Result<(), String>;
+
+impl KittyResponses {
+    /// Responses kept for ids that no state takes, e.g. of images that were dropped, or of other
+    /// programs.
+    const CAPACITY: usize = 256;
+
+    pub fn new() -> KittyResponses {
+        KittyResponses::default()
+    }
+
+    /// Parse the kitty responses in `input`, returning whether there were any.
+    ///
+    /// Unhandled escape sequences of the input stream can be fed as they are.
+    pub fn feed(&self, input: &str) -> bool {
+        let mut found = false;
+        let mut rest = input;
+        while let Some(start) = rest.find("\x1b_G") {
+            let response = &rest[start + 3..];
+            let Some(end) = response.find("\x1b\\") else {
+                break;
+            };
+            let (keys, message) = response[..end]
+                .split_once(';')
+                .unwrap_or((&response[..end], ""));
+            let id = keys
+                .split(',')
+                .find_map(|key| key.strip_prefix("i="))
+                .and_then(|id| id.parse().ok());
+            if let Some(id) = id {
+                let result = match message {
+                    "OK" => Ok(()),
+                    message => Err(message.to_string()),
+                };
+                let mut shared = lock(&self.shared);
+                shared.retain(|(other, _)| *other != id);
+                if shared.len() == KittyResponses::CAPACITY {
+                    shared.pop_front();
+                }
+                shared.push_back((id, result));
+                found = true;
+            }
+            rest = &response[end + 2..];
+        }
+        found
+    }
+
+    /// Take the last response for the image `id`, if any arrived.
+    fn take(&self, id: u32) -> Option<KittyResponse> {
+        let mut shared = lock(&self.shared);
+        let index = shared.iter().position(|(other, _)| *other == id)?;
+        shared.remove(index).map(|(_, response)| response)
+    }
+}
+
+/// Kitty commands for apps to write out after drawing.
+///
+/// Without it, kitty protocols emit their commands with the last cell of the buffer, so the image
+/// widget must be rendered after any other widget that draws that cell. With it, states and fixed
+/// protocols push their commands here instead, to be written after each draw:
+///
+/// ```rust,no_run
+/// # use ratatui::{backend::TestBackend, Terminal};
+/// # use ratatui_image::protocol::kitty::KittySequences;
+/// # fn draw(terminal: &mut Terminal<TestBackend>, sequences: &KittySequences, mut stdout: impl std::io::Write) -> std::io::Result<()> {
+/// terminal.draw(|f| {
+///     // Render the image widgets, in any order.
+/// })?;
+/// sequences.write_to(&mut stdout)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Cloning is cheap and clones share the same commands.
+#[derive(Clone, Debug, Default)]
+pub struct KittySequences {
+    shared: Arc<Mutex<String>>,
+}
+
+impl KittySequences {
+    pub fn new() -> KittySequences {
+        KittySequences::default()
+    }
+
+    /// Take the commands pushed since the last call.
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *lock(&self.shared))
+    }
+
+    /// Write out and flush the commands pushed since the last call, if there are any.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let seq = self.take();
+        if seq.is_empty() {
+            return Ok(());
+        }
+        out.write_all(seq.as_bytes())?;
+        out.flush()
+    }
+
+    fn push(&self, seq: &str) {
+        lock(&self.shared).push_str(seq);
+    }
+}
+
+/// Ask for responses to every command of `seq`, that are quiet (`q=2`) otherwise.
+fn ask_responses(seq: &str) -> String {
+    seq.replace("\x1b_Gq=2,", "\x1b_G")
+}
+
+/// How kitty states transmit image data to the terminal.
+///
+/// Files and shared memory skip pushing base64 data through the pty, but only work when the
+/// terminal runs on the same machine. See [crate::picker::Picker::query_kitty_medium].
+///
+/// [FixedKitty] always transmits directly, as it sends the same transmission on every redraw.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KittyMedium {
+    /// Base64 data inside the escape sequences (`t=d`), works everywhere.
+    #[default]
+    Direct,
+    /// A temporary file that the terminal reads and deletes (`t=t`).
+    TempFile,
+    /// A POSIX shared memory object that the terminal reads and unlinks (`t=s`).
+    SharedMemory,
+}
+
+impl KittyMedium {
+    /// Write `data` for the terminal to read, or `None` for [KittyMedium::Direct].
+    pub(crate) fn write(self, data: &[u8]) -> io::Result<Option<MediumFile>> {
+        match self {
+            KittyMedium::Direct => Ok(None),
+            KittyMedium::TempFile => write_temp_file(data).map(Some),
+            KittyMedium::SharedMemory => write_shared_memory(data).map(Some),
+        }
+    }
+
+    /// The escape sequence of a kitty query, asking whether the terminal can read a 1x1 image
+    /// from this medium. The returned file must be kept until the terminal has responded.
+    #[cfg(feature = "rustix")]
+    pub(crate) fn query(self) -> io::Result<(String, Option<MediumFile>)> {
+        let file = self.write(&[0, 0, 0])?;
+        let (medium, payload) = match &file {
+            Some(file) => (file.key(), file.payload()),
+            None => ("t=d", general_purpose::STANDARD.encode([0, 0, 0])),
+        };
+        let query = format!("\x1b_Gi={QUERY_ID},s=1,v=1,a=q,f=24,{medium};{payload}\x1b\\");
+        Ok((query, file))
+    }
+}
+
+/// The image id of queries, the terminal doesn't keep the image.
+#[cfg(feature = "rustix")]
+pub(crate) const QUERY_ID: u32 = 31;
+
+/// A file or shared memory object holding a payload for the terminal to read.
+///
+/// The terminal deletes it after reading it, it is deleted on drop if the terminal never did.
+#[derive(Debug)]
+pub(crate) enum MediumFile {
+    Temp(PathBuf),
+    #[cfg(unix)]
+    SharedMemory(std::ffi::CString),
+}
+
+impl MediumFile {
+    /// The transmission medium key of the escape sequence.
+    fn key(&self) -> &'static str {
+        match self {
+            MediumFile::Temp(_) => "t=t",
+            #[cfg(unix)]
+            MediumFile::SharedMemory(_) => "t=s",
+        }
+    }
+
+    /// The base64-encoded path or name, as payload of the escape sequence.
+    fn payload(&self) -> String {
+        match self {
+            MediumFile::Temp(path) => {
+                general_purpose::STANDARD.encode(path.as_os_str().to_string_lossy().as_bytes())
+            }
+            #[cfg(unix)]
+            MediumFile::SharedMemory(name) => general_purpose::STANDARD.encode(name.as_bytes()),
+        }
+    }
+}
+
+impl Drop for MediumFile {
+    fn drop(&mut self) {
+        match self {
+            MediumFile::Temp(path) => {
+                let _ = std::fs::remove_file(path);
+            }
+            #[cfg(unix)]
+            MediumFile::SharedMemory(name) => unsafe {
+                libc::shm_unlink(name.as_ptr());
+            },
+        }
+    }
+}
+
+/// Files of transmissions, kept until the terminal has read them.
+///
+/// The terminal deletes the files it reads. The files that have been sent are deleted here once
+/// the terminal has answered a later command, or after [PendingFiles::TIMEOUT] in case it never
+/// read them.
+///
+/// Cloning is cheap and clones share the same files.
+#[derive(Clone, Debug, Default)]
+struct PendingFiles {
+    files: Arc<Mutex<Vec<PendingFile>>>,
+}
+
+/// A file, with the time it was sent at if it was.
+type PendingFile = (MediumFile, Option<Instant>);
+
+impl PendingFiles {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Keep `file` until it has been sent and read, dropping files that will never be sent.
+    fn add(&self, file: MediumFile) {
+        let mut files = lock(&self.files);
+        files.retain(|(_, sent)| sent.is_some());
+        files.push((file, None));
+    }
+
+    /// Mark the files that were added as sent.
+    fn sent(&self) {
+        let now = Instant::now();
+        for (_, sent) in lock(&self.files).iter_mut() {
+            sent.get_or_insert(now);
+        }
+    }
+
+    /// Drop the files that have been sent, as the terminal answered a command sent after them.
+    fn answered(&self) {
+        lock(&self.files).retain(|(_, sent)| sent.is_none());
+    }
+
+    /// Drop the files that were sent too long ago.
+    fn expire(&self) {
+        lock(&self.files)
+            .retain(|(_, sent)| sent.map_or(true, |sent| sent.elapsed() < PendingFiles::TIMEOUT));
+    }
+}
+
+/// A number for unique file names within this process.
+fn next_file_number() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Kitty only deletes files from temporary directories with `tty-graphics-protocol` in the name.
+fn write_temp_file(data: &[u8]) -> io::Result<MediumFile> {
+    let name = format!(
+        "tty-graphics-protocol-ratatui-image-{}-{}",
+        std::process::id(),
+        next_file_number()
+    );
+    let file = MediumFile::Temp(std::env::temp_dir().join(name));
+    if let MediumFile::Temp(path) = &file {
+        std::fs::write(path, data)?;
+    }
+    Ok(file)
+}
+
+/// The name is kept short, as macOS allows only 31 characters.
+#[cfg(unix)]
+fn write_shared_memory(data: &[u8]) -> io::Result<MediumFile> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let name = format!(
+        "/ratatui-image-{}-{}",
+        std::process::id(),
+        next_file_number()
+    );
+    let name = std::ffi::CString::new(name)?;
+    let flags = libc::O_CREAT | libc::O_EXCL | libc::O_RDWR;
+    let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600 as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = MediumFile::SharedMemory(name);
+    // Closed on drop, the terminal opens the object by name.
+    let shm = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    shm.set_len(data.len() as u64)?;
+    if data.is_empty() {
+        return Ok(file);
+    }
+    // Shared memory can't be written to on macOS, only mapped.
+    unsafe {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            data.len(),
+            libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            shm.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.cast::<u8>(), data.len());
+        libc::munmap(ptr, data.len());
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn write_shared_memory(_data: &[u8]) -> io::Result<MediumFile> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// How kitty images are placed on the screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KittyPlacement {
+    /// Virtual placements shown through unicode placeholder cells (`U=1`), that scroll and get
+    /// removed together with the text.
+    #[default]
+    Placeholders,
+    /// Classic placements at the cursor (`a=p`), for terminals without unicode placeholders.
+    ///
+    /// Placements stay on screen until replaced, so states move them along with the widget, but
+    /// a widget that isn't rendered anymore must be cleared with [ResizeProtocol::clear].
+    Cursor,
+}
+
+impl KittyPlacement {
+    /// [KittyPlacement::Cursor] in WezTerm and Konsole, that lack unicode placeholders.
+    pub fn from_env() -> KittyPlacement {
+        let wezterm = std::env::var("TERM_PROGRAM").map_or(false, |program| program == "WezTerm");
+        if wezterm || std::env::var_os("KONSOLE_VERSION").is_some() {
+            KittyPlacement::Cursor
+        } else {
+            KittyPlacement::Placeholders
+        }
+    }
+
+    /// The placement for a terminal's XTVERSION response, if the terminal is known.
+    ///
+    /// Kitty added unicode placeholders in version 0.28.0.
+    pub fn from_xtversion(response: &str) -> Option<KittyPlacement> {
+        if response.contains("WezTerm") || response.contains("Konsole") {
+            return Some(KittyPlacement::Cursor);
+        }
+        let start = response.find("kitty(")? + "kitty(".len();
+        let version = &response[start..start + response[start..].find(')')?];
+        let mut parts = version
+            .split('.')
+            .map(|part| part.parse::<u32>().unwrap_or(0));
+        let (major, minor) = (parts.next()?, parts.next()?);
+        Some(if (major, minor) < (0, 28) {
+            KittyPlacement::Cursor
+        } else {
+            KittyPlacement::Placeholders
+        })
+    }
+}
+
+/// Where kitty states scale and crop images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KittyScaling {
+    /// Resize and crop before encoding, and transmit again for every new size.
+    #[default]
+    Client,
+    /// Transmit the full image once, and let the terminal scale and crop it for every new size,
+    /// crop and pan with placement parameters alone.
+    ///
+    /// [Resize::Fit] shows as much of the image as fits the aspect ratio of the cells, which cuts
+    /// off at most a fraction of a cell instead of stretching it. Only works with
+    /// [KittyPlacement::Placeholders].
+    Terminal,
+}
 
 // Fixed Kitty protocol (transmits image data on every render!)
+//
+// Always transmits directly (`t=d`): the terminal deletes the files it reads, while a fixed
+// protocol puts out the same transmission whenever its cells are redrawn.
 #[derive(Clone, Default)]
 pub struct FixedKitty {
-    transmit_data: String,
-    unique_id: u8,
+    payload: KittyPayload,
+    /// The transmission without placement, built once for all renders.
+    transmission: String,
+    unique_id: KittyId,
     rect: Rect,
+    stats: RenderStats,
+    placement: KittyPlacement,
+    sequences: Option<KittySequences>,
+    responses: Option<KittyResponses>,
+    /// The message of the last error response, shared as fixed protocols render through `&self`
+    error: Arc<Mutex<Option<String>>>,
 }
 
 impl FixedKitty {
@@ -28,35 +609,176 @@ impl FixedKitty {
         resize: Resize,
         background_color: Option<Rgb<u8>>,
         area: Rect,
-        id: u8,
+        id: impl Into<KittyId>,
+    ) -> Result<Self> {
+        Self::from_source_cached(
+            source,
+            resize,
+            background_color,
+            area,
+            id.into(),
+            Quality::default(),
+            None,
+        )
+    }
+
+    pub(crate) fn from_source_cached(
+        source: &ImageSource,
+        resize: Resize,
+        background_color: Option<Rgb<u8>>,
+        area: Rect,
+        id: KittyId,
+        quality: Quality,
+        cache: Option<&EncodingCache>,
     ) -> Result<Self> {
-        let (image, desired) = resize
-            .resize(source, Rect::default(), area, background_color, false)
-            .unwrap_or_else(|| (source.image.clone(), source.desired));
+        let desired = resize
+            .needs_resize(source, Rect::default(), area, false)
+            .unwrap_or(source.desired);
+        let key = EncodingKey::new(
+            source,
+            desired,
+            &resize,
+            background_color,
+            ProtocolType::Kitty,
+        )
+        .quality(quality);
+        let mut stats = RenderStats {
+            transmitted: true,
+            ..RenderStats::default()
+        };
+        let payload = cache::get_or_encode(cache, key, || {
+            let (image, _) = stats::timed(&mut stats.resize_time, || {
+                resize
+                    .resize(source, Rect::default(), area, background_color, false)
+                    .unwrap_or_else(|| ((*source.image).clone(), source.desired))
+            });
+            stats::timed(&mut stats.encode_time, || {
+                KittyPayload::encode(&image, desired, source.font_size, &quality)
+            })
+        })?;
 
-        let transmit_data = transmit_virtual(&image, id);
+        let transmission = transmit_only(&payload, &id, None);
+        stats.bytes = transmission.len();
         Ok(Self {
-            transmit_data,
+            payload,
+            transmission,
             unique_id: id,
             rect: desired,
+            stats,
+            placement: KittyPlacement::default(),
+            sequences: None,
+            responses: None,
+            error: Arc::default(),
         })
     }
+
+    /// Place with unicode placeholders, or at the cursor.
+    pub fn placement(mut self, placement: KittyPlacement) -> FixedKitty {
+        self.placement = placement;
+        self
+    }
+
+    /// Push commands to `sequences` instead of the buffer, see [KittySequences].
+    pub fn sequences(mut self, sequences: Option<KittySequences>) -> FixedKitty {
+        self.sequences = sequences;
+        self
+    }
+
+    /// Ask the terminal to answer every command, see [KittyState::responses].
+    pub fn responses(mut self, responses: Option<KittyResponses>) -> FixedKitty {
+        self.responses = responses;
+        self
+    }
+
+    /// Ask for responses if there are [FixedKitty::responses], and take the last one.
+    fn with_responses(&self, seq: String) -> String {
+        let Some(responses) = &self.responses else {
+            return seq;
+        };
+        if let Some(response) = responses.take(self.unique_id.id) {
+            *lock(&self.error) = response.err();
+        }
+        ask_responses(&seq)
+    }
 }
 
 impl Protocol for FixedKitty {
     fn render(&self, area: Rect, buf: &mut Buffer) {
-        let mut seq = Some(self.transmit_data.clone());
-        render(area, self.rect, buf, self.unique_id, &mut seq);
+        self.render_layer(area, buf, Layer::default());
+    }
+    fn render_layer(&self, area: Rect, buf: &mut Buffer, layer: Layer) {
+        let deletes = self.unique_id.take_deletes();
+        if self.placement == KittyPlacement::Cursor {
+            let seq = deletes
+                + &self.transmission
+                + &place_cursor(&self.payload, self.rect, area, &self.unique_id, layer);
+            render_cursor(area, buf, self.with_responses(seq), self.sequences.as_ref());
+            return;
+        }
+        let pixels = (self.payload.width, self.payload.height);
+        let seq = deletes
+            + &self.transmission
+            + &place_virtual(self.rect, &self.unique_id, layer, None, pixels);
+        render(
+            area,
+            self.rect,
+            buf,
+            &self.unique_id,
+            Some(self.with_responses(seq)),
+            layer,
+            self.sequences.as_ref(),
+        );
+    }
+    fn clear(&self, area: Rect, buf: &mut Buffer) {
+        clear_with_delete(
+            covered_area(self.rect, area),
+            buf,
+            &self.unique_id,
+            self.sequences.as_ref(),
+        );
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+    fn last_error(&self) -> Option<Error> {
+        let id = self.unique_id.id;
+        let message = lock(&self.error).clone()?;
+        Some(Error::Kitty { id, message })
     }
 }
 
 #[derive(Clone)]
 pub struct KittyState {
     source: ImageSource,
-    pub unique_id: u8,
+    unique_id: KittyId,
     rect: Rect,
     hash: u64,
     proto_state: KittyProtoState,
+    cache: Option<EncodingCache>,
+    generation: Generation,
+    /// The area covered at the last render
+    area: Rect,
+    telemetry: Telemetry,
+    stats: RenderStats,
+    quality: Quality,
+    layer: Layer,
+    medium: KittyMedium,
+    /// The files of transmissions, if not [KittyMedium::Direct]
+    files: PendingFiles,
+    placement: KittyPlacement,
+    /// The pixel size of the transmitted image, to crop cursor placements
+    pixels: (u32, u32),
+    /// Where the image was placed at the cursor, if it was
+    placed: Option<Rect>,
+    scaling: KittyScaling,
+    pan: (u32, u32),
+    /// The source rectangle of the placement, when cropping on the terminal
+    crop: Option<[u32; 4]>,
+    responses: Option<KittyResponses>,
+    sequences: Option<KittySequences>,
+    error: Option<Arc<Error>>,
+    /// The number of transmissions, for animations to send their frames after each
+    transmissions: u64,
 }
 
 #[derive(Default, Clone, PartialEq)]
@@ -64,113 +786,959 @@ enum KittyProtoState {
     #[default]
     Place,
     TransmitAndPlace(String),
+    /// Place the transmitted image again, with the same placement id.
+    Replace(String),
 }
 
 impl KittyState {
-    pub fn new(source: ImageSource, id: u8) -> KittyState {
+    pub fn new(source: ImageSource, id: impl Into<KittyId>) -> KittyState {
         KittyState {
             source,
-            unique_id: id,
+            unique_id: id.into(),
             rect: Rect::default(),
             hash: u64::default(),
             proto_state: KittyProtoState::default(),
+            cache: None,
+            generation: Generation::default(),
+            area: Rect::default(),
+            telemetry: Telemetry::default(),
+            stats: RenderStats::default(),
+            quality: Quality::default(),
+            layer: Layer::default(),
+            medium: KittyMedium::default(),
+            files: PendingFiles::default(),
+            placement: KittyPlacement::default(),
+            pixels: (0, 0),
+            placed: None,
+            scaling: KittyScaling::default(),
+            pan: (0, 0),
+            crop: None,
+            responses: None,
+            sequences: None,
+            error: None,
+            transmissions: 0,
         }
     }
+
+    /// Share encodings through an [EncodingCache].
+    ///
+    /// Only the image payload is shared, every state still transmits it with its own id.
+    pub fn cache(mut self, cache: Option<EncodingCache>) -> KittyState {
+        self.cache = cache;
+        self
+    }
+
+    /// Invalidate together with all other states holding a clone of `generation`.
+    pub fn generation(mut self, generation: Generation) -> KittyState {
+        self.generation = generation;
+        self
+    }
+
+    /// Add [RenderStats] up in a shared [Telemetry].
+    pub fn telemetry(mut self, telemetry: Telemetry) -> KittyState {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Encode with the pixels per cell, compression and budget of `quality`.
+    pub fn quality(mut self, quality: Quality) -> KittyState {
+        self.quality = quality;
+        self
+    }
+
+    /// Transmit through `medium`, falling back to [KittyMedium::Direct] if writing fails.
+    ///
+    /// With [KittyState::responses], it also falls back if the terminal answers with an error,
+    /// and transmits the image again at the next render.
+    pub fn medium(mut self, medium: KittyMedium) -> KittyState {
+        self.medium = medium;
+        self
+    }
+
+    /// Place with unicode placeholders, or at the cursor.
+    pub fn placement(mut self, placement: KittyPlacement) -> KittyState {
+        self.placement = placement;
+        self
+    }
+
+    /// Ask the terminal to answer every command, with responses that are fed to `responses`.
+    pub fn responses(mut self, responses: Option<KittyResponses>) -> KittyState {
+        self.responses = responses;
+        self
+    }
+
+    /// Push commands to `sequences` instead of the buffer, see [KittySequences].
+    pub fn sequences(mut self, sequences: Option<KittySequences>) -> KittyState {
+        self.sequences = sequences;
+        self
+    }
+
+    /// Scale and crop before encoding, or on the terminal.
+    pub fn scaling(mut self, scaling: KittyScaling) -> KittyState {
+        self.scaling = scaling;
+        self
+    }
+
+    pub fn id(&self) -> &KittyId {
+        &self.unique_id
+    }
+
+    fn scales_on_terminal(&self) -> bool {
+        self.scaling == KittyScaling::Terminal && self.placement == KittyPlacement::Placeholders
+    }
+
+    /// The source rectangle `[x, y, w, h]` in transmitted pixels, to crop `rect` on the terminal.
+    fn crop(&self, resize: &Resize, rect: Rect) -> Option<[u32; 4]> {
+        let desired = self.source.desired;
+        if desired.width == 0 || desired.height == 0 || rect.width == 0 || rect.height == 0 {
+            return None;
+        }
+        // The full image was encoded for the desired cells.
+        let (width, height) = self.pixels;
+        let cell = (width / desired.width as u32, height / desired.height as u32);
+        let (w, h) = (rect.width as u32 * cell.0, rect.height as u32 * cell.1);
+        if *resize == Resize::Fit {
+            // The fitted cells have the aspect ratio of the image only up to a fraction of a cell.
+            // Show the largest part from the top left corner with the aspect ratio of the cells,
+            // cutting off padding or a fraction of a cell, rather than stretching the image.
+            let (w, h) = (w as u64, h as u64);
+            let fit_w = (height as u64 * w / h.max(1)).min(width as u64) as u32;
+            let fit_h = (width as u64 * h / w.max(1)).min(height as u64) as u32;
+            return ((fit_w, fit_h) != (width, height)).then_some([0, 0, fit_w, fit_h]);
+        }
+        let (font_width, font_height) = self.source.font_size;
+        let x = self.pan.0 * cell.0 / font_width.max(1) as u32;
+        let y = self.pan.1 * cell.1 / font_height.max(1) as u32;
+        Some([
+            x.min(width.saturating_sub(w)),
+            y.min(height.saturating_sub(h)),
+            w,
+            h,
+        ])
+    }
+
+    /// Handle the last response of the terminal, that arrived since the last render.
+    ///
+    /// An OK clears the error of an earlier response. An error while transmitting through a file
+    /// falls back to [KittyMedium::Direct], transmitting again at the next render.
+    fn take_response(&mut self) {
+        let id = self.unique_id.id;
+        let response = self
+            .responses
+            .as_ref()
+            .and_then(|responses| responses.take(id));
+        match response {
+            Some(Ok(())) => {
+                self.error = None;
+                self.files.answered();
+            }
+            Some(Err(message)) => {
+                self.error = Some(Arc::new(Error::Kitty { id, message }));
+                self.files.answered();
+                if self.medium != KittyMedium::Direct {
+                    self.medium = KittyMedium::Direct;
+                    self.reset();
+                }
+            }
+            None => {}
+        }
+        self.files.expire();
+    }
+
+    /// Send `seq` after any pending transmission, e.g. to place the transmitted image again.
+    fn send_after(&mut self, seq: String) {
+        self.proto_state = match std::mem::take(&mut self.proto_state) {
+            KittyProtoState::TransmitAndPlace(pending) => {
+                KittyProtoState::TransmitAndPlace(pending + &seq)
+            }
+            KittyProtoState::Replace(pending) => KittyProtoState::Replace(pending + &seq),
+            KittyProtoState::Place => KittyProtoState::Replace(seq),
+        };
+    }
 }
 
 impl ResizeProtocol for KittyState {
     fn rect(&self) -> Rect {
         self.rect
     }
-    fn render(
-        &mut self,
-        resize: &Resize,
-        background_color: Option<Rgb<u8>>,
-        area: Rect,
-        buf: &mut Buffer,
-    ) {
+    fn source(&self) -> Option<&ImageSource> {
+        Some(&self.source)
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        Some(self.source)
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        let force = self.source.hash != self.hash || self.generation.is_stale();
+        if self.scales_on_terminal() && !force {
+            let rect = resize.needs_resize_rect(self.source.desired, area);
+            let changed = rect != self.rect || self.crop(resize, rect) != self.crop;
+            return changed.then_some(rect);
+        }
+        resize.needs_resize(&self.source, self.rect, area, force)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
         if area.width == 0 || area.height == 0 {
             return;
         }
 
-        let force = self.source.hash != self.hash;
-        if let Some((img, rect)) =
-            resize.resize(&self.source, self.rect, area, background_color, force)
-        {
-            let data = transmit_virtual(&img, self.unique_id);
-            self.hash = self.source.hash;
-            self.rect = rect;
-            self.proto_state = KittyProtoState::TransmitAndPlace(data);
+        let stale = self.generation.update();
+        let force = stale || self.source.hash != self.hash;
+        let on_terminal = self.scales_on_terminal();
+        if on_terminal && !force {
+            // Already transmitted, only place it again.
+            let rect = resize.needs_resize_rect(self.source.desired, area);
+            let crop = self.crop(resize, rect);
+            if rect != self.rect || crop != self.crop {
+                self.send_after(place_virtual(
+                    rect,
+                    &self.unique_id,
+                    self.layer,
+                    crop,
+                    self.pixels,
+                ));
+                self.rect = rect;
+                self.crop = crop;
+            }
+            return;
         }
-
-        // Transmit only once
-        let mut seq = match &mut self.proto_state {
-            KittyProtoState::TransmitAndPlace(seq) => {
-                let seq = std::mem::take(seq);
-                self.proto_state = KittyProtoState::Place;
-                Some(seq)
+        let needs_resize = if on_terminal {
+            // The full image, as it would be encoded for its desired size.
+            Some(self.source.desired)
+        } else {
+            resize.needs_resize(&self.source, self.rect, area, force)
+        };
+        // Terminal scaling encodes like a fitted image, so that the encoding can be shared.
+        let encode_resize = if on_terminal { &Resize::Fit } else { resize };
+        if let Some(rect) = needs_resize {
+            let key = EncodingKey::new(
+                &self.source,
+                rect,
+                encode_resize,
+                background_color,
+                ProtocolType::Kitty,
+            )
+            .quality(self.quality);
+            let mut stats = RenderStats::default();
+            let encoded = cache::get_or_encode(self.cache.as_ref(), key, || {
+                let img = stats::timed(&mut stats.resize_time, || {
+                    encode_resize.resize_to(&self.source, rect, background_color)
+                });
+                stats::timed(&mut stats.encode_time, || {
+                    KittyPayload::encode(&img, rect, self.source.font_size, &self.quality)
+                })
+            });
+            let payload = match encoded {
+                Ok(payload) => payload,
+                Err(err) => {
+                    self.error = Some(Arc::new(err));
+                    return;
+                }
+            };
+            {
+                let file = general_purpose::STANDARD
+                    .decode(&payload.data)
+                    .ok()
+                    .and_then(|data| self.medium.write(&data).ok().flatten());
+                self.pixels = (payload.width, payload.height);
+                let (rect, crop) = if on_terminal {
+                    let rect = resize.needs_resize_rect(self.source.desired, area);
+                    (rect, self.crop(resize, rect))
+                } else {
+                    (rect, None)
+                };
+                let data = stats::timed(&mut stats.encode_time, || match self.placement {
+                    _ if on_terminal => {
+                        transmit_only(&payload, &self.unique_id, file.as_ref())
+                            + &place_virtual(rect, &self.unique_id, self.layer, crop, self.pixels)
+                    }
+                    KittyPlacement::Placeholders => {
+                        transmit_virtual(&payload, rect, &self.unique_id, self.layer, file.as_ref())
+                    }
+                    KittyPlacement::Cursor => {
+                        transmit_only(&payload, &self.unique_id, file.as_ref())
+                    }
+                });
+                self.crop = crop;
+                if let Some(file) = file {
+                    self.files.add(file);
+                }
+                self.hash = self.source.hash;
+                self.rect = rect;
+                self.proto_state = KittyProtoState::TransmitAndPlace(data);
+                self.transmissions += 1;
+                self.stats = stats;
+                self.telemetry.record_encode(&stats);
+                self.error = None;
             }
-            KittyProtoState::Place => None,
+        }
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.area = covered_area(self.rect, area);
+        self.take_response();
+        // Transmit only once
+        let (mut seq, transmitted) = match std::mem::take(&mut self.proto_state) {
+            KittyProtoState::TransmitAndPlace(seq) => (Some(seq), true),
+            KittyProtoState::Replace(seq) => (Some(seq), false),
+            KittyProtoState::Place => (None, false),
         };
+        if self.placement == KittyPlacement::Cursor && (seq.is_some() || self.placed != Some(area))
+        {
+            let payload = KittyPayload {
+                width: self.pixels.0,
+                height: self.pixels.1,
+                ..KittyPayload::default()
+            };
+            // Delete the placement where the widget was, before placing it where it is.
+            let delete = match self.placed {
+                Some(placed) if placed != area => {
+                    let (id, placement) = (self.unique_id.id, self.unique_id.placement);
+                    format!("\x1b_Gq=2,a=d,d=i,i={id},p={placement}\x1b\\")
+                }
+                _ => String::new(),
+            };
+            let place = place_cursor(&payload, self.rect, area, &self.unique_id, self.layer);
+            seq = Some(delete + &seq.unwrap_or_default() + &place);
+            self.placed = Some(area);
+        }
+        let deletes = self.unique_id.take_deletes();
+        if !deletes.is_empty() {
+            seq = Some(deletes + &seq.unwrap_or_default());
+        }
+        if self.responses.is_some() {
+            seq = seq.map(|seq| ask_responses(&seq));
+        }
+        if transmitted {
+            self.files.sent();
+        }
+        self.stats.transmitted = transmitted;
+        self.stats.bytes = seq.as_ref().map_or(0, String::len);
+        self.telemetry.record_render(&self.stats);
 
-        render(area, self.rect, buf, self.unique_id, &mut seq);
+        match self.placement {
+            KittyPlacement::Placeholders => {
+                let sequences = self.sequences.as_ref();
+                render(
+                    area,
+                    self.rect,
+                    buf,
+                    &self.unique_id,
+                    seq,
+                    self.layer,
+                    sequences,
+                )
+            }
+            KittyPlacement::Cursor => {
+                render_cursor(area, buf, seq.unwrap_or_default(), self.sequences.as_ref())
+            }
+        }
     }
     fn reset(&mut self) {
         self.rect = Rect::default();
         self.hash = u64::default();
         self.proto_state = KittyProtoState::default();
+        self.placed = None;
     }
+    fn clear(&mut self, buf: &mut Buffer) {
+        clear_with_delete(self.area, buf, &self.unique_id, self.sequences.as_ref());
+        self.area = Rect::default();
+        // The image is gone from the terminal's memory, so it must be transmitted again.
+        self.reset();
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        if layer == self.layer {
+            return;
+        }
+        self.layer = layer;
+        if self.placement == KittyPlacement::Cursor {
+            // Placed again with the new z-index at the next render.
+            self.placed = None;
+            return;
+        }
+        // The z-index is part of the placement, so place it again over the same placement id.
+        if self.rect.width > 0 {
+            self.send_after(place_virtual(
+                self.rect,
+                &self.unique_id,
+                layer,
+                self.crop,
+                self.pixels,
+            ));
+        }
+    }
+    fn set_pan(&mut self, pan: (u32, u32)) {
+        self.pan = pan;
+    }
+    fn last_error(&self) -> Option<&Error> {
+        self.error.as_deref()
+    }
+}
+
+/// A frame of a [KittyAnimationState], shown for `gap` before the next frame.
+#[derive(Clone)]
+pub struct KittyFrame {
+    pub image: Arc<DynamicImage>,
+    pub gap: Duration,
+}
+
+impl From<image::Frame> for KittyFrame {
+    /// Frames of GIF or APNG decoders, see [image::AnimationDecoder::into_frames].
+    fn from(frame: image::Frame) -> KittyFrame {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        KittyFrame {
+            gap: Duration::from_millis((numer / denom.max(1)) as u64),
+            image: Arc::new(DynamicImage::ImageRgba8(frame.into_buffer())),
+        }
+    }
+}
+
+/// Whether the terminal plays a [KittyAnimationState].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KittyPlayback {
+    #[default]
+    Play,
+    Pause,
+}
+
+/// State for animated images, that the terminal plays by itself.
+///
+/// All frames are transmitted once, at the size of the first frame, and the terminal scales and
+/// crops them like [KittyScaling::Terminal]. Playing, pausing and looping only send a short
+/// command, never the frames again. Frames are always transmitted directly.
+///
+/// With [KittyPlacement::Cursor], which can't scale on the terminal, all frames are encoded and
+/// transmitted again for every new size.
+///
+/// The first frame is encoded when rendering, the other frames on a worker thread. They are sent
+/// at the first render after they are ready, so apps should keep rendering, e.g. on a tick.
+///
+/// # Example
+/// ```rust
+/// # use std::{fs::File, io::BufReader};
+/// use image::{codecs::gif::GifDecoder, AnimationDecoder};
+/// use ratatui_image::protocol::kitty::{KittyAnimationState, KittyFrame, KittyId};
+///
+/// # fn load() -> Result<(), Box<dyn std::error::Error>> {
+/// let decoder = GifDecoder::new(BufReader::new(File::open("animation.gif")?))?;
+/// let frames = decoder
+///     .into_frames()
+///     .map(|frame| frame.map(KittyFrame::from))
+///     .collect::<Result<Vec<_>, _>>()?;
+/// let state = KittyAnimationState::new(frames, (7, 14), KittyId::new(1)).loops(Some(3));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KittyAnimationState {
+    /// Transmits and places the first frame.
+    inner: KittyState,
+    /// The frames after the first one.
+    frames: Vec<KittyFrame>,
+    /// The gap of the first frame.
+    gap: Duration,
+    playback: KittyPlayback,
+    loops: Option<u32>,
+    /// The frames being encoded on a worker thread.
+    pending: Option<PendingFrames>,
+}
+
+/// The escape sequences of the frames and their encoding time, once the worker is done.
+type PendingFrames = Arc<Mutex<Option<Result<(String, Duration)>>>>;
+
+impl KittyAnimationState {
+    pub fn new(
+        frames: Vec<KittyFrame>,
+        font_size: FontSize,
+        id: impl Into<KittyId>,
+    ) -> KittyAnimationState {
+        let mut frames = frames.into_iter();
+        let (image, gap) = match frames.next() {
+            Some(first) => (first.image, first.gap),
+            None => (Arc::new(DynamicImage::default()), Duration::ZERO),
+        };
+        let source = ImageSource::new(image, font_size);
+        KittyAnimationState {
+            inner: KittyState::new(source, id).scaling(KittyScaling::Terminal),
+            frames: frames.collect(),
+            gap,
+            playback: KittyPlayback::default(),
+            loops: None,
+            pending: None,
+        }
+    }
+
+    /// Invalidate together with all other states holding a clone of `generation`.
+    pub fn generation(mut self, generation: Generation) -> KittyAnimationState {
+        self.inner = self.inner.generation(generation);
+        self
+    }
+
+    /// Add [RenderStats] up in a shared [Telemetry].
+    pub fn telemetry(mut self, telemetry: Telemetry) -> KittyAnimationState {
+        self.inner = self.inner.telemetry(telemetry);
+        self
+    }
+
+    /// Encode with the pixels per cell, compression and budget of `quality`.
+    pub fn quality(mut self, quality: Quality) -> KittyAnimationState {
+        self.inner = self.inner.quality(quality);
+        self
+    }
+
+    /// See [KittyState::placement].
+    pub fn placement(mut self, placement: KittyPlacement) -> KittyAnimationState {
+        self.inner = self.inner.placement(placement);
+        self
+    }
+
+    /// See [KittyState::responses].
+    pub fn responses(mut self, responses: Option<KittyResponses>) -> KittyAnimationState {
+        self.inner = self.inner.responses(responses);
+        self
+    }
+
+    /// See [KittyState::sequences].
+    pub fn sequences(mut self, sequences: Option<KittySequences>) -> KittyAnimationState {
+        self.inner = self.inner.sequences(sequences);
+        self
+    }
+
+    /// Play the animation this many times, or forever with `None` (the default).
+    pub fn loops(mut self, loops: Option<u32>) -> KittyAnimationState {
+        self.set_loops(loops);
+        self
+    }
+
+    pub fn id(&self) -> &KittyId {
+        self.inner.id()
+    }
+
+    pub fn playback(&self) -> KittyPlayback {
+        self.playback
+    }
+
+    pub fn play(&mut self) {
+        self.set_playback(KittyPlayback::Play);
+    }
+
+    pub fn pause(&mut self) {
+        self.set_playback(KittyPlayback::Pause);
+    }
+
+    /// Play or pause at the next render.
+    pub fn set_playback(&mut self, playback: KittyPlayback) {
+        if playback != self.playback {
+            self.playback = playback;
+            self.control();
+        }
+    }
+
+    /// Loop this many times from the next render, or forever with `None`.
+    pub fn set_loops(&mut self, loops: Option<u32>) {
+        if loops != self.loops {
+            self.loops = loops;
+            self.control();
+        }
+    }
+
+    /// Send the playback and loops, if the frames were transmitted.
+    fn control(&mut self) {
+        if self.inner.rect.width > 0 {
+            let seq = self.control_seq();
+            self.inner.send_after(seq);
+        }
+    }
+
+    fn control_seq(&self) -> String {
+        let id = self.inner.unique_id.id;
+        let state = match self.playback {
+            KittyPlayback::Play => 3,
+            KittyPlayback::Pause => 1,
+        };
+        // One more than the number of loops, 1 loops forever.
+        let loops = self.loops.map_or(1, |loops| loops.saturating_add(1));
+        format!("\x1b_Gq=2,a=a,i={id},s={state},v={loops}\x1b\\")
+    }
+
+    /// Encode the frames after the first one on a worker thread, like the first frame was.
+    fn spawn_frames(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>) {
+        let inner = &self.inner;
+        let (rect, resize) = if inner.scales_on_terminal() {
+            (inner.source.desired, Resize::Fit)
+        } else {
+            (inner.rect, resize.clone())
+        };
+        let job = FramesJob {
+            frames: self.frames.clone(),
+            gap: self.gap,
+            id: inner.unique_id.clone(),
+            font_size: inner.source.font_size,
+            quality: inner.quality,
+            rect,
+            resize,
+            background_color,
+        };
+        let pending = PendingFrames::default();
+        // Replaces the frames of an earlier transmission, whose worker's result is dropped.
+        self.pending = Some(pending.clone());
+        thread::spawn(move || {
+            let mut encode_time = Duration::ZERO;
+            let seq = stats::timed(&mut encode_time, || job.transmit());
+            *lock(&pending) = Some(seq.map(|seq| (seq, encode_time)));
+        });
+    }
+
+    /// Send the frames after the first one and the playback, if the worker is done.
+    fn poll_frames(&mut self) {
+        let Some(result) = self
+            .pending
+            .as_ref()
+            .and_then(|pending| lock(pending).take())
+        else {
+            return;
+        };
+        self.pending = None;
+        match result {
+            Ok((seq, encode_time)) => {
+                self.inner.stats.encode_time += encode_time;
+                let seq = seq + &self.control_seq();
+                self.inner.send_after(seq);
+            }
+            Err(err) => self.inner.error = Some(Arc::new(err)),
+        }
+    }
+}
+
+/// The frames after the first one, resized and encoded like the first one.
+struct FramesJob {
+    frames: Vec<KittyFrame>,
+    /// The gap of the first frame.
+    gap: Duration,
+    id: KittyId,
+    font_size: FontSize,
+    quality: Quality,
+    rect: Rect,
+    resize: Resize,
+    background_color: Option<Rgb<u8>>,
 }
 
-fn render(area: Rect, rect: Rect, buf: &mut Buffer, id: u8, seq: &mut Option<String>) {
-    // Draw each line of unicode placeholders but all into the first cell.
-    // I couldn't work out actually drawing into each cell of the buffer so
-    // that `.set_skip(true)` would be made unnecessary. Maybe some other escape
-    // sequence gets sneaked in somehow.
-    // It could also be made so that each cell starts and ends its own escape sequence
-    // with the image id, but maybe that's worse.
-    for y in 0..(area.height.min(rect.height)) {
-        let mut symbol = seq.take().unwrap_or_default();
+impl FramesJob {
+    /// Create the kitty escape sequences for the gap of the first frame and the other frames.
+    fn transmit(&self) -> Result<String> {
+        let (id, rect, font_size) = (&self.id, self.rect, self.font_size);
+        let mut seq = format!("\x1b_Gq=2,a=a,i={},r=1{}\x1b\\", id.id, gap_key(self.gap));
+        for frame in &self.frames {
+            let source = ImageSource::with_hash(frame.image.clone(), font_size, ImageHash::Sampled);
+            let img = self.resize.resize_to(&source, rect, self.background_color);
+            let payload = KittyPayload::encode(&img, rect, font_size, &self.quality)?;
+            let action = format!("a=f{}", gap_key(frame.gap));
+            seq += &transmit(&payload, id, None, &action);
+        }
+        Ok(seq)
+    }
+}
 
-        // Start unicode placeholder sequence
-        symbol.push_str(&format!("\x1b[38;5;{id}m"));
-        add_placeholder(&mut symbol, 0, y);
+/// The gap key of a frame, omitted for zero so that the terminal uses its default.
+fn gap_key(gap: Duration) -> String {
+    match gap.as_millis() {
+        0 => String::new(),
+        ms => format!(",z={ms}"),
+    }
+}
 
-        for x in 1..(area.width.min(rect.width)) {
-            // Add entire row with positions
-            add_placeholder(&mut symbol, x, y);
-            // Skip or something may overwrite it
-            buf.get_mut(area.left() + x, area.top() + y).set_skip(true);
+impl ResizeProtocol for KittyAnimationState {
+    fn rect(&self) -> Rect {
+        self.inner.rect()
+    }
+    fn source(&self) -> Option<&ImageSource> {
+        self.inner.source()
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        Some(self.inner.source)
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        self.inner.needs_resize(resize, area)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
+        let transmissions = self.inner.transmissions;
+        self.inner.resize_encode(resize, background_color, area);
+        // The first frame was transmitted again, which drops all other frames.
+        if self.inner.transmissions != transmissions {
+            self.spawn_frames(resize, background_color);
         }
-        symbol.push_str("\x1b[0m"); // Stop messing with styles now.
-        buf.get_mut(area.left(), area.top() + y).set_symbol(&symbol);
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.poll_frames();
+        self.inner.render_current(area, buf);
+    }
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+    fn clear(&mut self, buf: &mut Buffer) {
+        self.inner.clear(buf);
+    }
+    fn stats(&self) -> RenderStats {
+        self.inner.stats()
+    }
+    fn last_error(&self) -> Option<&Error> {
+        self.inner.last_error()
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        self.inner.set_layer(layer);
+    }
+    fn set_pan(&mut self, pan: (u32, u32)) {
+        self.inner.set_pan(pan);
+    }
+}
+
+/// Blank the area, and delete the image from the terminal's memory.
+///
+/// The delete command is emitted even if the area is empty or outside of the buffer, as the image
+/// may have been transmitted anyway.
+fn clear_with_delete(
+    area: Rect,
+    buf: &mut Buffer,
+    id: &KittyId,
+    sequences: Option<&KittySequences>,
+) {
+    clear_area(area, buf);
+    let id = id.id;
+    emit(buf, sequences, &format!("\x1b_Gq=2,a=d,d=I,i={id}\x1b\\"));
+}
+
+fn render(
+    area: Rect,
+    rect: Rect,
+    buf: &mut Buffer,
+    id: &KittyId,
+    seq: Option<String>,
+    layer: Layer,
+    sequences: Option<&KittySequences>,
+) {
+    // Every cell is a unicode placeholder of its own, with the lower 24 bits of the image id as
+    // foreground color and the placement id of its tile as underline color. Its row and column in
+    // the tile, and the high byte of the id, are diacritics, so that cells can be diffed, redrawn
+    // or covered one by one.
+    //
+    // Below text, cells that have text are left alone.
+    let below_text = layer.is_below_text();
+    let [high, r, g, b] = id.id.to_be_bytes();
+    let columns = tile_count(rect.width);
+    for y in 0..area.height.min(rect.height) {
+        for x in 0..area.width.min(rect.width) {
+            let cell = buf.get_mut(area.left() + x, area.top() + y);
+            if below_text && has_text(cell) {
+                continue;
+            }
+            let index = (y / TILE) as u32 * columns + (x / TILE) as u32;
+            let [_, pr, pg, pb] = tile_placement(id, index).to_be_bytes();
+            let style = Style::default()
+                .fg(Color::Rgb(r, g, b))
+                .underline_color(Color::Rgb(pr, pg, pb));
+            let mut symbol = String::from(PLACEHOLDER);
+            symbol.extend([diacritic(y % TILE), diacritic(x % TILE)]);
+            if high != 0 {
+                symbol.push(diacritic(high as u16));
+            }
+            cell.set_symbol(&symbol).set_style(style);
+        }
+    }
+    if let Some(seq) = seq {
+        emit(buf, sequences, &seq);
+    }
+}
+
+/// Push `seq` to the `sequences`, or emit it with the last cell of the buffer if there are none.
+fn emit(buf: &mut Buffer, sequences: Option<&KittySequences>, seq: &str) {
+    match sequences {
+        Some(sequences) => sequences.push(seq),
+        None => emit_last(buf, seq),
+    }
+}
+
+/// Emit `seq` with the last cell of the buffer.
+///
+/// Ratatui counts the printable characters of escape sequences as the width of a cell, and doesn't
+/// draw as many cells after it. There are none after the last cell. Widgets that are rendered
+/// afterwards and draw the last cell overwrite it, see [KittySequences].
+fn emit_last(buf: &mut Buffer, seq: &str) {
+    let area = buf.area;
+    if area.width == 0 || area.height == 0 {
+        return;
+    }
+    let cell = buf.get_mut(area.right() - 1, area.bottom() - 1);
+    let symbol = format!("{seq}{}", cell.symbol);
+    cell.set_symbol(&symbol);
+}
+
+/// Render at the first cell of `area`, moving the cursor there for `seq` and back afterwards.
+///
+/// The cells keep their content. Text is drawn above or below the image depending on its layer.
+fn render_cursor(area: Rect, buf: &mut Buffer, seq: String, sequences: Option<&KittySequences>) {
+    if seq.is_empty() || !area.intersects(buf.area) {
+        return;
+    }
+    let area = area.intersection(buf.area);
+    let (row, column) = (area.top() + 1, area.left() + 1);
+    // Save the cursor (DECSC), move it to the cell, and restore it (DECRC).
+    emit(
+        buf,
+        sequences,
+        &format!("\x1b7\x1b[{row};{column}H{seq}\x1b8"),
+    );
+}
+
+/// Base64-encoded image data, independent of the kitty image id.
+#[derive(Clone, Default)]
+struct KittyPayload {
+    width: u32,
+    height: u32,
+    /// The format of the data, never [KittyFormat::Auto].
+    format: KittyFormat,
+    data: String,
+}
+
+impl KittyPayload {
+    /// Uncompressed size of one base64-encoded RGB8 pixel.
+    const BYTES_PER_PIXEL: f64 = 4.0;
+
+    /// Encode an image that was resized for `rect`, with the pixels per cell and format of
+    /// `quality`.
+    fn encode(
+        img: &DynamicImage,
+        rect: Rect,
+        font_size: FontSize,
+        quality: &Quality,
+    ) -> Result<KittyPayload> {
+        let img = quality::downscale(
+            img,
+            rect,
+            KittyPayload::cell_pixels(rect, font_size, quality),
+        );
+        let img_rgb8 = img.to_rgb8();
+        let format = quality.kitty_format.resolve(img.width(), img.height());
+        let bytes = match format {
+            KittyFormat::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                // Writing into a Vec can't fail.
+                let _ = encoder.write_all(img_rgb8.as_raw());
+                encoder.finish().unwrap_or_default()
+            }
+            KittyFormat::Png => {
+                let mut buffer = Cursor::new(Vec::new());
+                DynamicImage::ImageRgb8(img_rgb8).write_to(&mut buffer, ImageOutputFormat::Png)?;
+                buffer.into_inner()
+            }
+            KittyFormat::Rgb | KittyFormat::Auto => img_rgb8.into_raw(),
+        };
+        Ok(KittyPayload {
+            width: img.width(),
+            height: img.height(),
+            format,
+            data: general_purpose::STANDARD.encode(bytes),
+        })
+    }
+
+    /// The pixels per cell only depend on the key, so that [Encoded::from_disk] can restore them.
+    fn cell_pixels(rect: Rect, font_size: FontSize, quality: &Quality) -> FontSize {
+        quality.cell_pixels_for_budget(font_size, rect, KittyPayload::BYTES_PER_PIXEL)
+    }
+}
+
+impl Encoded for KittyPayload {
+    fn byte_size(&self) -> usize {
+        self.data.len()
+    }
+    fn to_disk(&self) -> Option<&str> {
+        Some(&self.data)
+    }
+    fn from_disk(data: String, key: &EncodingKey) -> Option<Self> {
+        let (width, height) = KittyPayload::cell_pixels(key.rect, key.font_size, &key.quality);
+        let (width, height) = (
+            (key.rect.width * width) as u32,
+            (key.rect.height * height) as u32,
+        );
+        Some(KittyPayload {
+            width,
+            height,
+            format: key.quality.kitty_format.resolve(width, height),
+            data,
+        })
     }
 }
 
 /// Create a kitty escape sequence for transmitting and virtual-placement.
 ///
-/// The image will be transmitted as RGB8 in chunks of 4096 bytes.
-/// A "virtual placement" (U=1) is created so that we can place it using unicode placeholders.
-/// Removing the placements when the unicode placeholder is no longer there is being handled
-/// automatically by kitty.
-fn transmit_virtual(img: &DynamicImage, id: u8) -> String {
-    let (w, h) = (img.width(), img.height());
-    let img_rgb8 = img.to_rgb8();
-    let bytes = img_rgb8.as_raw();
+/// The image will be transmitted as RGB8 or PNG in chunks of 3072 bytes (4096 in base64), or
+/// through a `file` that the terminal reads.
+/// A "virtual placement" (U=1) of `rect` cells is created so that we can place it using unicode
+/// placeholders, the image is scaled to the cells. Removing the placements when the unicode
+/// placeholder is no longer there is being handled automatically by kitty.
+/// The placement has the z-index of the `layer`, negative values are drawn below text.
+fn transmit_virtual(
+    payload: &KittyPayload,
+    rect: Rect,
+    id: &KittyId,
+    layer: Layer,
+    file: Option<&MediumFile>,
+) -> String {
+    if is_tiled(rect) {
+        let pixels = (payload.width, payload.height);
+        return transmit_only(payload, id, file) + &place_virtual(rect, id, layer, None, pixels);
+    }
+    let placement = id.placement;
+    let (cols, rows) = (rect.width, rect.height);
+    let z = layer.z_index();
+    let action = format!("p={placement},a=T,U=1,c={cols},r={rows},z={z}");
+    transmit(payload, id, file, &action)
+}
+
+/// Create a kitty escape sequence for transmitting only, to be placed with [place_cursor].
+fn transmit_only(payload: &KittyPayload, id: &KittyId, file: Option<&MediumFile>) -> String {
+    transmit(payload, id, file, "a=t")
+}
+
+/// Kitty takes at most 4096 bytes of base64 per chunk.
+pub(crate) const CHUNK_SIZE: usize = 4096;
+
+fn transmit(
+    payload: &KittyPayload,
+    id: &KittyId,
+    file: Option<&MediumFile>,
+    action: &str,
+) -> String {
+    let id = id.id;
+    let (w, h) = (payload.width, payload.height);
+    // The size of PNG images is part of the data.
+    let format = match payload.format {
+        KittyFormat::Png => "f=100".to_string(),
+        KittyFormat::Zlib => format!("f=24,s={w},v={h},o=z"),
+        KittyFormat::Rgb | KittyFormat::Auto => format!("f=24,s={w},v={h}"),
+    };
+
+    if let Some(file) = file {
+        let (medium, path) = (file.key(), file.payload());
+        return format!("\x1b_Gq=2,i={id},{action},{format},{medium};{path}\x1b\\");
+    }
 
     let mut str = String::new();
 
-    let mut payload: String;
-    let chunks = bytes.chunks(4000);
+    let chunks: Vec<&str> = payload
+        .data
+        .as_bytes()
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
     let chunk_count = chunks.len();
-    for (i, chunk) in chunks.enumerate() {
-        payload = general_purpose::STANDARD.encode(chunk);
+    for (i, payload) in chunks.into_iter().enumerate() {
         match i {
             0 => {
-                // Transmit and virtual-place but keep sending chunks
+                // Transmit (and virtual-place) but keep sending chunks
                 let more = if chunk_count > 1 { 1 } else { 0 };
                 str.push_str(&format!(
-                    "\x1b_Gq=2,i={id},a=T,U=1,f=24,t=d,s={w},v={h},m={more};{payload}\x1b\\"
+                    "\x1b_Gq=2,i={id},{action},{format},t=d,m={more};{payload}\x1b\\"
                 ));
             }
             n if n + 1 == chunk_count => {
@@ -186,15 +1754,111 @@ fn transmit_virtual(img: &DynamicImage, id: u8) -> String {
     str
 }
 
-fn add_placeholder(str: &mut String, x: u16, y: u16) {
-    str.push('\u{10EEEE}');
-    str.push(diacritic(y));
-    str.push(diacritic(x));
+/// Create a kitty escape sequence to place an already transmitted image again, which replaces the
+/// placement with the same placement id.
+///
+/// The `crop` source rectangle `[x, y, w, h]` of the image is scaled to the cells of `rect`, or
+/// the whole image of `pixels` if there is none. Images larger than a tile are placed tile by
+/// tile, each with its share of the source rectangle.
+fn place_virtual(
+    rect: Rect,
+    id: &KittyId,
+    layer: Layer,
+    crop: Option<[u32; 4]>,
+    pixels: (u32, u32),
+) -> String {
+    let z = layer.z_index();
+    let place = |placement: u32, cols: u16, rows: u16, crop: Option<[u32; 4]>| {
+        let id = id.id;
+        let crop = match crop {
+            Some([x, y, w, h]) => format!(",x={x},y={y},w={w},h={h}"),
+            None => String::new(),
+        };
+        format!("\x1b_Gq=2,i={id},p={placement},a=p,U=1,c={cols},r={rows},z={z}{crop}\x1b\\")
+    };
+    if !is_tiled(rect) {
+        return place(id.placement, rect.width, rect.height, crop);
+    }
+    let [x, y, w, h] = crop.unwrap_or([0, 0, pixels.0, pixels.1]);
+    // The source pixels of the cells `from..to` out of `cells`.
+    let span = |start: u32, size: u32, from: u16, to: u16, cells: u16| {
+        let at = |cell: u16| start + (size as u64 * cell as u64 / cells as u64) as u32;
+        (at(from), at(to) - at(from))
+    };
+    tiles(rect)
+        .map(|(index, tile)| {
+            let (tile_x, tile_w) = span(x, w, tile.left(), tile.right(), rect.width);
+            let (tile_y, tile_h) = span(y, h, tile.top(), tile.bottom(), rect.height);
+            let crop = Some([tile_x, tile_y, tile_w, tile_h]);
+            place(tile_placement(id, index), tile.width, tile.height, crop)
+        })
+        .collect()
+}
+
+/// Cells per side of a tile, as placeholders have diacritics for 297 rows and columns.
+const TILE: u16 = 297;
+
+/// Whether `rect` is placed in more than one tile.
+fn is_tiled(rect: Rect) -> bool {
+    rect.width > TILE || rect.height > TILE
 }
 
+/// The number of tiles to cover `cells`.
+fn tile_count(cells: u16) -> u32 {
+    ((cells as u32 + TILE as u32 - 1) / TILE as u32).max(1)
+}
+
+/// The tiles of `rect` with their index, in cells relative to `rect`, row by row.
+fn tiles(rect: Rect) -> impl Iterator<Item = (u32, Rect)> {
+    let columns = tile_count(rect.width);
+    (0..tile_count(rect.height)).flat_map(move |row| {
+        (0..columns).map(move |column| {
+            let (x, y) = (column as u16 * TILE, row as u16 * TILE);
+            let tile = Rect::new(
+                x,
+                y,
+                (rect.width - x).min(TILE),
+                (rect.height - y).min(TILE),
+            );
+            (row * columns + column, tile)
+        })
+    })
+}
+
+/// The placement id of the tile `index`, counting up from the placement id of `id`.
+fn tile_placement(id: &KittyId, index: u32) -> u32 {
+    (id.placement - 1 + index) % 0xFF_FFFF + 1
+}
+
+/// Create a kitty escape sequence to place an already transmitted image at the cursor, without
+/// moving the cursor. Only the part of the image's `rect` that fits into `area` is shown.
+fn place_cursor(
+    payload: &KittyPayload,
+    rect: Rect,
+    area: Rect,
+    id: &KittyId,
+    layer: Layer,
+) -> String {
+    let (id, placement) = (id.id, id.placement);
+    let (cols, rows) = (area.width.min(rect.width), area.height.min(rect.height));
+    let z = layer.z_index();
+    // Crop the source pixels to the visible cells, rather than squeezing the image into them.
+    let crop = if cols < rect.width || rows < rect.height {
+        let w = payload.width * cols as u32 / rect.width as u32;
+        let h = payload.height * rows as u32 / rect.height as u32;
+        format!(",w={w},h={h}")
+    } else {
+        String::new()
+    };
+    format!("\x1b_Gq=2,i={id},p={placement},a=p,C=1,c={cols},r={rows},z={z}{crop}\x1b\\")
+}
+
+/// The unicode placeholder for kitty images.
+const PLACEHOLDER: char = '\u{10EEEE}';
+
 /// From https://sw.kovidgoyal.net/kitty/_downloads/1792bad15b12979994cd6ecc54c967a6/rowcolumn-diacritics.txt
 /// See https://sw.kovidgoyal.net/kitty/graphics-protocol/#unicode-placeholders for further explanation.
-static DIACRITICS: [char; 297] = [
+static DIACRITICS: [char; TILE as usize] = [
     '\u{305}',
     '\u{30D}',
     '\u{30E}',
@@ -493,10 +2157,521 @@ static DIACRITICS: [char; 297] = [
     '\u{1D243}',
     '\u{1D244}',
 ];
+/// The diacritic encoding a row or column within a tile, or a byte.
 fn diacritic(y: u16) -> char {
-    if y >= DIACRITICS.len() as u16 {
-        DIACRITICS[0]
-    } else {
-        DIACRITICS[y as usize]
+    DIACRITICS[y as usize % DIACRITICS.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholder(buf: &Buffer, x: u16, y: u16) -> Option<String> {
+        let cell = buf.get(x, y);
+        let symbol = cell.symbol.strip_prefix(PLACEHOLDER)?;
+        Some(symbol.to_string())
+    }
+
+    #[test]
+    #[should_panic(expected = "between 1 and 0xFFFFFF")]
+    fn placement_out_of_range() {
+        let _ = KittyId::new(1).placement(0x100_0000);
+    }
+
+    #[test]
+    fn render_cells() {
+        let rect = Rect::new(0, 0, 3, 2);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 5, 3));
+        let id = KittyId::new(0x0102_0304).placement(5);
+        render(
+            Rect::new(1, 1, 4, 2),
+            rect,
+            &mut buf,
+            &id,
+            Some("seq".to_string()),
+            Layer::ABOVE,
+            None,
+        );
+
+        let high = DIACRITICS[1];
+        for (x, y) in [(0, 0), (2, 0), (0, 1), (2, 1)] {
+            let diacritics: String = [DIACRITICS[y], DIACRITICS[x], high].into_iter().collect();
+            assert_eq!(
+                Some(diacritics),
+                placeholder(&buf, x as u16 + 1, y as u16 + 1)
+            );
+            let cell = buf.get(x as u16 + 1, y as u16 + 1);
+            assert_eq!(Color::Rgb(2, 3, 4), cell.fg);
+            assert_eq!(Color::Rgb(0, 0, 5), cell.underline_color);
+            assert!(!cell.skip);
+        }
+        assert_eq!(None, placeholder(&buf, 0, 1));
+        assert_eq!(None, placeholder(&buf, 4, 1));
+        // The transmission is emitted last.
+        assert_eq!("seq ", buf.get(4, 2).symbol);
+    }
+
+    #[test]
+    fn render_wider_than_diacritics() {
+        let rect = Rect::new(0, 0, 400, 2);
+        let mut buf = Buffer::empty(rect);
+        render(
+            rect,
+            rect,
+            &mut buf,
+            &KittyId::new(1),
+            None,
+            Layer::ABOVE,
+            None,
+        );
+
+        for (y, row) in DIACRITICS.iter().take(2).enumerate() {
+            for x in 0..400 {
+                let column = DIACRITICS[x % 297];
+                let diacritics: String = [*row, column].into_iter().collect();
+                assert_eq!(Some(diacritics), placeholder(&buf, x as u16, y as u16));
+            }
+        }
+        // The second tile has a placement id of its own.
+        assert_eq!(Color::Rgb(0, 0, 1), buf.get(296, 0).underline_color);
+        assert_eq!(Color::Rgb(0, 0, 2), buf.get(297, 0).underline_color);
+    }
+
+    #[test]
+    fn render_below_text_wider_than_diacritics() {
+        let rect = Rect::new(0, 0, 400, 1);
+        let mut buf = Buffer::empty(rect);
+        buf.get_mut(100, 0).set_symbol("a");
+        buf.get_mut(350, 0).set_symbol("b");
+        render(
+            rect,
+            rect,
+            &mut buf,
+            &KittyId::new(1),
+            None,
+            Layer::BELOW_TEXT,
+            None,
+        );
+
+        assert_eq!("a", buf.get(100, 0).symbol);
+        assert!(placeholder(&buf, 101, 0).is_some());
+        assert!(placeholder(&buf, 349, 0).is_some());
+        assert_eq!("b", buf.get(350, 0).symbol);
+        // Runs past the last diacritic are placed in the next tile.
+        let diacritics: String = [DIACRITICS[0], DIACRITICS[351 - 297]].into_iter().collect();
+        assert_eq!(Some(diacritics), placeholder(&buf, 351, 0));
+    }
+
+    #[test]
+    fn transmit_formats() {
+        let img: DynamicImage = image::RgbImage::new(40, 40).into();
+        let rect = Rect::new(0, 0, 4, 4);
+        let transmit = |kitty_format| {
+            let quality = Quality {
+                kitty_format,
+                ..Quality::FULL
+            };
+            let payload = KittyPayload::encode(&img, rect, (10, 10), &quality).unwrap();
+            transmit_virtual(&payload, rect, &KittyId::new(1), Layer::ABOVE, None)
+        };
+
+        assert!(transmit(KittyFormat::Rgb).contains(",f=24,s=40,v=40,t=d,"));
+        assert!(transmit(KittyFormat::Zlib).contains(",f=24,s=40,v=40,o=z,t=d,"));
+        let png = transmit(KittyFormat::Png);
+        assert!(png.contains(",f=100,t=d,"));
+        // The PNG signature.
+        assert!(png.contains(";iVBORw0KGgo"));
+        assert_eq!(png, transmit(KittyFormat::Auto));
+        assert!(png.len() < transmit(KittyFormat::Rgb).len());
+
+        // 4800 bytes of RGB are 6400 in base64, sent in two chunks.
+        let chunks: Vec<usize> = transmit(KittyFormat::Rgb)
+            .split("\x1b\\")
+            .filter_map(|command| command.split_once(';'))
+            .map(|(_, data)| data.len())
+            .collect();
+        assert_eq!(vec![4096, 2304], chunks);
+    }
+
+    #[test]
+    fn transmit_temp_file() {
+        let payload = KittyPayload {
+            width: 1,
+            height: 1,
+            format: KittyFormat::Rgb,
+            data: general_purpose::STANDARD.encode([1, 2, 3]),
+        };
+        let file = KittyMedium::TempFile.write(&[1, 2, 3]).unwrap().unwrap();
+        let MediumFile::Temp(path) = &file else {
+            unreachable!()
+        };
+        let path = path.clone();
+        assert_eq!(vec![1, 2, 3], std::fs::read(&path).unwrap());
+
+        let rect = Rect::new(0, 0, 1, 1);
+        let transmit =
+            transmit_virtual(&payload, rect, &KittyId::new(1), Layer::ABOVE, Some(&file));
+        let encoded = general_purpose::STANDARD.encode(path.to_string_lossy().as_bytes());
+        assert!(transmit.contains(&format!(",c=1,r=1,z=0,f=24,s=1,v=1,t=t;{encoded}\x1b\\")));
+
+        // The terminal never read it.
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn placement_from_xtversion() {
+        let placement = |response| KittyPlacement::from_xtversion(response);
+        assert_eq!(
+            Some(KittyPlacement::Placeholders),
+            placement("\x1bP>|kitty(0.31.0)\x1b\\")
+        );
+        assert_eq!(
+            Some(KittyPlacement::Cursor),
+            placement("\x1bP>|kitty(0.26.5)\x1b\\")
+        );
+        assert_eq!(
+            Some(KittyPlacement::Cursor),
+            placement("\x1bP>|WezTerm 20230712-072601-f4abf8fd\x1b\\")
+        );
+        assert_eq!(None, placement("\x1bP>|foot(1.16.1)\x1b\\"));
+    }
+
+    #[test]
+    fn render_cursor_placement() {
+        let area = Rect::new(2, 1, 4, 2);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 4));
+        buf.get_mut(3, 1).set_symbol("a");
+        let payload = KittyPayload {
+            width: 40,
+            height: 40,
+            format: KittyFormat::Rgb,
+            data: "AAAA".to_string(),
+        };
+        let kitty = FixedKitty {
+            transmission: transmit_only(&payload, &KittyId::default(), None),
+            payload,
+            rect: Rect::new(0, 0, 4, 4),
+            ..FixedKitty::default()
+        }
+        .placement(KittyPlacement::Cursor);
+        kitty.render_layer(area, &mut buf, Layer::BELOW_TEXT);
+
+        // Emitted with the last cell, at the first cell of the area.
+        let symbol = &buf.get(7, 3).symbol;
+        assert!(symbol.starts_with("\x1b7\x1b[2;3H\x1b_Gq=2,i=1,a=t,"));
+        // Cropped to the upper half, that fits.
+        assert!(symbol.ends_with("a=p,C=1,c=4,r=2,z=-1,w=40,h=20\x1b\\\x1b8 "));
+        // The cells of the area are left alone.
+        assert_eq!(" ", buf.get(2, 1).symbol);
+        assert_eq!("a", buf.get(3, 1).symbol);
+        assert!(!buf.get(3, 1).skip);
+    }
+
+    #[test]
+    fn move_cursor_placement() {
+        let sequences = KittySequences::new();
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let mut state = KittyState::new(source, KittyId::new(3))
+            .placement(KittyPlacement::Cursor)
+            .sequences(Some(sequences.clone()));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+        state.render(&Resize::Fit, None, Rect::new(0, 0, 2, 2), &mut buf);
+        assert!(!sequences.take().contains("a=d"));
+
+        state.render(&Resize::Fit, None, Rect::new(4, 4, 2, 2), &mut buf);
+        assert_eq!(
+            "\x1b7\x1b[5;5H\x1b_Gq=2,a=d,d=i,i=3,p=1\x1b\\\x1b_Gq=2,i=3,p=1,a=p,C=1,c=2,r=2,z=0\x1b\\\x1b8",
+            sequences.take()
+        );
+    }
+
+    #[test]
+    fn scale_on_terminal() {
+        let image: DynamicImage = image::RgbImage::new(80, 80).into();
+        let source = ImageSource::new(image, (10, 10));
+        let mut state = KittyState::new(source, KittyId::new(1)).scaling(KittyScaling::Terminal);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+
+        state.render(&Resize::Crop, None, Rect::new(0, 0, 8, 8), &mut buf);
+        assert!(state.stats().transmitted);
+        assert_eq!(Rect::new(0, 0, 8, 8), state.rect());
+
+        // Cropped and panned by the placement alone.
+        state.set_pan((25, 10));
+        assert_eq!(
+            Some(Rect::new(0, 0, 4, 2)),
+            state.needs_resize(&Resize::Crop, Rect::new(0, 0, 4, 2))
+        );
+        state.render(&Resize::Crop, None, Rect::new(0, 0, 4, 2), &mut buf);
+        assert!(!state.stats().transmitted);
+        let symbol = &buf.get(7, 7).symbol;
+        assert!(symbol.starts_with("\x1b_Gq=2,i=1,p=1,a=p,U=1,c=4,r=2,z=0,x=25,y=10,w=40,h=20"));
+
+        // Scaled to fit.
+        assert_eq!(
+            None,
+            state.needs_resize(&Resize::Crop, Rect::new(0, 0, 4, 2))
+        );
+        state.render(&Resize::Fit, None, Rect::new(0, 0, 4, 2), &mut buf);
+        assert!(!state.stats().transmitted);
+        assert_eq!(Rect::new(0, 0, 2, 2), state.rect());
+    }
+
+    #[test]
+    fn fit_on_terminal() {
+        let image: DynamicImage = image::RgbImage::new(30, 20).into();
+        let source = ImageSource::new(image, (10, 10));
+        let mut state = KittyState::new(source, KittyId::new(1)).scaling(KittyScaling::Terminal);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert_eq!(Rect::new(0, 0, 2, 1), state.rect());
+        // The upper 2:1 part of the 3:2 image, instead of squeezing all of it.
+        assert!(buf
+            .get(1, 1)
+            .symbol
+            .ends_with("a=p,U=1,c=2,r=1,z=0,x=0,y=0,w=30,h=15\x1b\\ "));
+    }
+
+    #[test]
+    fn responses() {
+        let responses = KittyResponses::new();
+        assert!(!responses.feed("\x1b[?62;4c"));
+        assert!(responses.feed("\x1b_Gi=3;OK\x1b\\\x1b_Gi=4,p=1;ENOSPC:out of space\x1b\\"));
+        assert_eq!(Some(Ok(())), responses.take(3));
+        assert_eq!(
+            Some(Err("ENOSPC:out of space".to_string())),
+            responses.take(4)
+        );
+        assert_eq!(None, responses.take(4));
+        // Bounded, with one response per id.
+        for id in 0..300 {
+            responses.feed(&format!("\x1b_Gi={id};OK\x1b\\\x1b_Gi={id};EINVAL\x1b\\"));
+        }
+        assert_eq!(KittyResponses::CAPACITY, lock(&responses.shared).len());
+        assert_eq!(None, responses.take(43));
+        assert_eq!(Some(Err("EINVAL".to_string())), responses.take(44));
+        lock(&responses.shared).clear();
+
+        let image: DynamicImage = image::RgbImage::new(20, 20).into();
+        let source = ImageSource::new(image, (10, 10));
+        let mut state = KittyState::new(source, KittyId::new(4)).responses(Some(responses.clone()));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        // Not quiet.
+        assert!(buf.get(1, 1).symbol.starts_with("\x1b_Gi=4,p=1,a=T,"));
+        assert!(state.last_error().is_none());
+
+        responses.feed("\x1b_Gi=4;EBADPNG:bad\x1b\\");
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert_eq!(
+            "kitty image 4: EBADPNG:bad",
+            state.last_error().unwrap().to_string()
+        );
+        // Cleared by encoding again.
+        state.reset();
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert!(state.last_error().is_none());
+
+        let fixed = FixedKitty::default().responses(Some(responses.clone()));
+        responses.feed("\x1b_Gi=1;ENOENT:gone\x1b\\");
+        fixed.render(buf.area, &mut buf);
+        assert_eq!(
+            "kitty image 1: ENOENT:gone",
+            fixed.last_error().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn fixed_renders_unchanged() {
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let area = Rect::new(0, 0, 2, 2);
+        let fixed =
+            FixedKitty::from_source(&source, Resize::Fit, None, area, KittyId::new(1)).unwrap();
+        let (mut prev, mut next) = (Buffer::empty(area), Buffer::empty(area));
+        fixed.render(area, &mut prev);
+        fixed.render(area, &mut next);
+        assert!(prev.get(1, 1).symbol.contains(",t=d,"));
+        assert_eq!(0, prev.diff(&next).len());
+    }
+
+    #[test]
+    fn medium_fallback() {
+        let (responses, sequences) = (KittyResponses::new(), KittySequences::new());
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let mut state = KittyState::new(source, KittyId::new(6))
+            .medium(KittyMedium::TempFile)
+            .responses(Some(responses.clone()))
+            .sequences(Some(sequences.clone()));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert!(sequences.take().contains(",t=t;"));
+        // Kept until the terminal has answered.
+        let path = match &lock(&state.files.files)[0].0 {
+            MediumFile::Temp(path) => path.clone(),
+            #[cfg(unix)]
+            MediumFile::SharedMemory(_) => unreachable!(),
+        };
+        assert!(path.exists());
+
+        responses.feed("\x1b_Gi=6;EBADF:cannot read\x1b\\");
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert!(state.last_error().is_some());
+        assert!(!path.exists());
+        state.render(&Resize::Fit, None, buf.area, &mut buf);
+        assert!(sequences.take().contains(",t=d,"));
+    }
+
+    #[test]
+    fn delete_dropped_ids() {
+        let ids = KittyIds::new();
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let area = Rect::new(0, 0, 2, 2);
+        let dropped = KittyState::new(source.clone(), ids.allocate());
+        drop(dropped);
+        // Not reused until the image has been deleted.
+        let mut state = KittyState::new(source, ids.allocate());
+        assert_eq!(2, state.unique_id.id());
+
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 3));
+        state.render(&Resize::Fit, None, area, &mut buf);
+        assert!(buf
+            .get(2, 2)
+            .symbol
+            .starts_with("\x1b_Gq=2,a=d,d=I,i=1\x1b\\\x1b_Gq=2,i=2,"));
+        assert_eq!("", ids.take_deletes());
+        assert_eq!(1, ids.allocate().id());
+    }
+
+    #[test]
+    fn sequences() {
+        let sequences = KittySequences::new();
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let mut state = KittyState::new(source, KittyId::new(7)).sequences(Some(sequences.clone()));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 3));
+        state.render(&Resize::Fit, None, Rect::new(0, 0, 2, 2), &mut buf);
+        assert_eq!(" ", buf.get(2, 2).symbol);
+        assert!(placeholder(&buf, 1, 1).is_some());
+
+        let mut out = Vec::new();
+        sequences.write_to(&mut out).unwrap();
+        assert!(out.starts_with(b"\x1b_Gq=2,i=7,p=1,a=T,"));
+        state.clear(&mut buf);
+        assert_eq!("\x1b_Gq=2,a=d,d=I,i=7\x1b\\", sequences.take());
+        assert_eq!(" ", buf.get(2, 2).symbol);
+    }
+
+    #[test]
+    fn clear_outside_buffer() {
+        let source = ImageSource::new(DynamicImage::new_rgb8(2, 2), (1, 1));
+        let mut state = KittyState::new(source, KittyId::new(7));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 3));
+        state.render(&Resize::Fit, None, Rect::new(0, 0, 2, 2), &mut buf);
+        // Scrolled out of view, but the image is still in the terminal's memory.
+        let mut buf = Buffer::empty(Rect::new(0, 10, 3, 3));
+        state.clear(&mut buf);
+        assert_eq!("\x1b_Gq=2,a=d,d=I,i=7\x1b\\ ", buf.get(2, 12).symbol);
+    }
+
+    /// Wait for the worker to encode the frames of `state`.
+    fn wait_for_frames(state: &KittyAnimationState) {
+        let pending = state.pending.as_ref().expect("frames are being encoded");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while lock(pending).is_none() {
+            assert!(Instant::now() < deadline, "frames not encoded in time");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn animation() {
+        let frame = |gap| KittyFrame {
+            image: Arc::new(image::RgbImage::new(20, 20).into()),
+            gap: Duration::from_millis(gap),
+        };
+        let mut state = KittyAnimationState::new(
+            vec![frame(100), frame(50), frame(0)],
+            (10, 10),
+            KittyId::new(5),
+        )
+        .loops(Some(2));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        state.resize_encode(&Resize::Fit, None, buf.area);
+        wait_for_frames(&state);
+        state.render_current(buf.area, &mut buf);
+        let seq = buf.get(1, 1).symbol.clone();
+        let first = seq.find("a=t,").unwrap();
+        let gap = seq.find("\x1b_Gq=2,a=a,i=5,r=1,z=100\x1b\\").unwrap();
+        let second = seq.find("i=5,a=f,z=50,f=24,s=20,v=20,").unwrap();
+        let third = seq.find("i=5,a=f,f=24,").unwrap();
+        assert!(first < gap && gap < second && second < third);
+        let control = seq.find("\x1b_Gq=2,a=a,i=5,s=3,v=3\x1b\\").unwrap();
+        assert!(third < control);
+
+        // Only the playback is sent.
+        state.pause();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        state.resize_encode(&Resize::Fit, None, buf.area);
+        state.render_current(buf.area, &mut buf);
+        let seq = &buf.get(1, 1).symbol;
+        assert!(seq.starts_with("\x1b_Gq=2,a=a,i=5,s=1,v=3\x1b\\"));
+        assert!(!seq.contains("a=f"));
+    }
+
+    #[test]
+    fn animation_at_cursor() {
+        let frame = KittyFrame {
+            image: Arc::new(image::RgbImage::new(20, 20).into()),
+            gap: Duration::ZERO,
+        };
+        let sequences = KittySequences::new();
+        let mut state =
+            KittyAnimationState::new(vec![frame.clone(), frame], (10, 10), KittyId::new(5))
+                .placement(KittyPlacement::Cursor)
+                .sequences(Some(sequences.clone()));
+        let mut buf = Buffer::empty(Rect::new(0, 0, 1, 1));
+        state.resize_encode(&Resize::Fit, None, buf.area);
+        state.render_current(buf.area, &mut buf);
+        assert!(sequences.take().contains("a=p,C=1,c=1,r=1"));
+
+        // Sent at the first render after they are ready, at the size of the first frame.
+        wait_for_frames(&state);
+        state.render_current(buf.area, &mut buf);
+        assert!(sequences.take().contains("i=5,a=f,f=24,s=10,v=10,"));
+    }
+
+    #[test]
+    fn render_taller_than_diacritics() {
+        let rect = Rect::new(0, 0, 1, 300);
+        let mut buf = Buffer::empty(rect);
+        render(
+            rect,
+            rect,
+            &mut buf,
+            &KittyId::new(1),
+            None,
+            Layer::ABOVE,
+            None,
+        );
+
+        assert!(placeholder(&buf, 0, 296).is_some());
+        let diacritics: String = [DIACRITICS[2], DIACRITICS[0]].into_iter().collect();
+        assert_eq!(Some(diacritics), placeholder(&buf, 0, 299));
+        assert_eq!(Color::Rgb(0, 0, 2), buf.get(0, 299).underline_color);
+    }
+
+    #[test]
+    fn place_tiles() {
+        let rect = Rect::new(0, 0, 2, 300);
+        let id = KittyId::new(1).placement(7);
+        let seq = place_virtual(rect, &id, Layer::ABOVE, None, (20, 600));
+        assert_eq!(
+            seq,
+            "\x1b_Gq=2,i=1,p=7,a=p,U=1,c=2,r=297,z=0,x=0,y=0,w=20,h=594\x1b\\\
+             \x1b_Gq=2,i=1,p=8,a=p,U=1,c=2,r=3,z=0,x=0,y=594,w=20,h=6\x1b\\"
+        );
+        // Small images are placed once, without cropping.
+        let seq = place_virtual(Rect::new(0, 0, 2, 3), &id, Layer::ABOVE, None, (20, 6));
+        assert_eq!(seq, "\x1b_Gq=2,i=1,p=7,a=p,U=1,c=2,r=3,z=0\x1b\\");
     }
 }
@@ -1,51 +1,277 @@
 //! Protocol backends for the widgets
 
 use std::{
-    collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use dyn_clone::DynClone;
 use image::{DynamicImage, Rgb};
-use ratatui::{buffer::Buffer, layout::Rect};
+use ratatui::{
+    buffer::{Buffer, Cell},
+    layout::Rect,
+};
 
-use crate::FontSize;
+use crate::{cache::Fnv, stats::RenderStats, Error, FontSize, Layer, Result};
 
 use super::Resize;
 
 pub mod halfblocks;
-pub mod kitty;
 pub mod iterm;
-#[cfg(feature = "sixel")]
+pub mod kitty;
+#[cfg(any(feature = "sixel", feature = "sixel-native"))]
 pub mod sixel;
 
 /// A fixed image protocol for the [crate::FixedImage] widget.
 pub trait Protocol: Send + Sync {
     fn render(&self, area: Rect, buf: &mut Buffer);
+    /// Render on a [Layer], ignoring the layer by default.
+    fn render_layer(&self, area: Rect, buf: &mut Buffer, _layer: Layer) {
+        self.render(area, buf);
+    }
+    /// Remove the image that was rendered at `area`, when it is no longer going to be rendered.
+    ///
+    /// The cells are blanked so that ratatui's buffer diffing redraws them, which also gets rid
+    /// of graphics that some terminals would otherwise leave behind.
+    fn clear(&self, area: Rect, buf: &mut Buffer) {
+        clear_area(area, buf);
+    }
+    /// Statistics of the encoding, see [RenderStats].
+    ///
+    /// A fixed protocol is encoded once, and puts out all of its data whenever its cells are
+    /// redrawn.
+    fn stats(&self) -> RenderStats {
+        RenderStats::default()
+    }
+    /// The last error that the terminal reported, see [ResizeProtocol::last_error].
+    ///
+    /// Fixed protocols render through a shared reference, so the error is returned by value.
+    fn last_error(&self) -> Option<Error> {
+        None
+    }
 }
 
 /// A resizing image protocol for the [crate::ResizeImage] widget.
+///
+/// Only [ResizeProtocol::rect] is required. A protocol that implements just
+/// [ResizeProtocol::render] works with [crate::ResizeImage], but not with
+/// [crate::thread::ThreadProtocol], which needs [ResizeProtocol::needs_resize],
+/// [ResizeProtocol::resize_encode] and [ResizeProtocol::render_current].
 pub trait ResizeProtocol: Send + Sync + DynClone {
     fn rect(&self) -> Rect;
+    /// The [ImageSource] that this state resizes and encodes from, if it keeps one.
+    fn source(&self) -> Option<&ImageSource> {
+        None
+    }
+    /// Give up the state and take its [ImageSource], e.g. to build a state with another protocol
+    /// with [crate::picker::Picker::new_state_from_source].
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        None
+    }
+    /// Check if the image needs to be resized and re-encoded for `area`, returning the new rect.
+    ///
+    /// Never needs to by default.
+    fn needs_resize(&self, _resize: &Resize, _area: Rect) -> Option<Rect> {
+        None
+    }
+    /// Resize and encode the image for `area` if necessary, without rendering anything.
+    fn resize_encode(&mut self, _resize: &Resize, _background_color: Option<Rgb<u8>>, _area: Rect) {
+    }
+    /// Render the current encoding as it is, without resizing.
+    fn render_current(&mut self, _area: Rect, _buf: &mut Buffer) {}
+    /// Resize and encode if necessary, and render.
     fn render(
         &mut self,
         resize: &Resize,
         background_color: Option<Rgb<u8>>,
         area: Rect,
         buf: &mut Buffer,
-    );
+    ) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        self.resize_encode(resize, background_color, area);
+        self.render_current(area, buf);
+    }
+    /// Drop the current encoding, so that the next render resizes, encodes and transmits again.
+    ///
+    /// Should be called after the terminal has been cleared or has lost the graphics by other
+    /// means, e.g. when coming back from a suspended shell. Make sure that ratatui redraws every
+    /// cell as well, with `Terminal::clear`.
+    ///
     /// This method is optional.
     fn reset(&mut self) {}
+    /// Remove the image from where it was last rendered, when it is no longer going to be
+    /// rendered.
+    ///
+    /// Like [Protocol::clear], but the state knows its last area. This is not done on [Drop],
+    /// because states are cloned (e.g. for [crate::thread::ThreadProtocol]) and a dropped clone
+    /// must not remove the image of the original. The kitty images of dropped states are deleted
+    /// from the terminal's memory later, see [kitty::KittyIds::take_deletes].
+    ///
+    /// This method is optional.
+    fn clear(&mut self, _buf: &mut Buffer) {}
+    /// Statistics of the last encoding and the last render, see [RenderStats].
+    fn stats(&self) -> RenderStats {
+        RenderStats::default()
+    }
+    /// The error of the last encoding, or of the last transmission if the terminal reports it.
+    ///
+    /// A failed encoding keeps the previous encoding, if any.
+    fn last_error(&self) -> Option<&Error> {
+        None
+    }
+    /// Render on a [Layer] from now on, ignoring the layer by default.
+    fn set_layer(&mut self, _layer: Layer) {}
+    /// Show [Resize::Crop] images from `pan`, in pixels of the source image, instead of from the
+    /// top-left corner. Ignored by default.
+    ///
+    /// Only kitty states with [kitty::KittyScaling::Terminal] pan, and only with [Resize::Crop],
+    /// as they crop on the terminal without encoding again.
+    fn set_pan(&mut self, _pan: (u32, u32)) {}
+}
+
+/// Factory for custom protocols, that can be registered with
+/// [crate::picker::Picker::register] and used like the built-in protocols.
+///
+/// # Example
+/// ```rust
+/// use image::Rgb;
+/// use ratatui::layout::Rect;
+/// use ratatui_image::{
+///     picker::{Picker, ProtocolType},
+///     protocol::{
+///         halfblocks::{FixedHalfblocks, HalfblocksState},
+///         ImageSource, Protocol, ProtocolFactory, ResizeProtocol,
+///     },
+///     Resize, Result,
+/// };
+///
+/// // Some in-house protocol, that just happens to work like halfblocks.
+/// struct MyProtocol;
+///
+/// impl ProtocolFactory for MyProtocol {
+///     fn new_static(
+///         &self,
+///         source: &ImageSource,
+///         resize: Resize,
+///         background_color: Option<Rgb<u8>>,
+///         area: Rect,
+///     ) -> Result<Box<dyn Protocol>> {
+///         let fixed = FixedHalfblocks::from_source(source, resize, background_color, area)?;
+///         Ok(Box::new(fixed))
+///     }
+///     fn new_state(&self, source: ImageSource) -> Box<dyn ResizeProtocol> {
+///         Box::new(HalfblocksState::new(source))
+///     }
+/// }
+///
+/// let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+/// let my_protocol = picker.register(MyProtocol).unwrap();
+/// picker.set(my_protocol).unwrap();
+/// ```
+pub trait ProtocolFactory: Send + Sync {
+    /// Build a fixed protocol for [crate::FixedImage], see [crate::picker::Picker::new_static_fit].
+    fn new_static(
+        &self,
+        source: &ImageSource,
+        resize: Resize,
+        background_color: Option<Rgb<u8>>,
+        area: Rect,
+    ) -> Result<Box<dyn Protocol>>;
+    /// Build a state protocol for [crate::ResizeImage], see [crate::picker::Picker::new_state].
+    fn new_state(&self, source: ImageSource) -> Box<dyn ResizeProtocol>;
+}
+
+/// Blank all cells of `area`, so that ratatui redraws them on the next frame.
+pub(crate) fn clear_area(area: Rect, buf: &mut Buffer) {
+    // Ratatui's intersection overflows for rects that don't intersect.
+    if !area.intersects(buf.area) {
+        return;
+    }
+    let area = area.intersection(buf.area);
+    for y in area.top()..area.bottom() {
+        for x in area.left()..area.right() {
+            buf.get_mut(x, y).reset();
+        }
+    }
+}
+
+/// Whether a cell has text, that an image on a layer below text should leave alone.
+pub(crate) fn has_text(cell: &Cell) -> bool {
+    !cell.skip && cell.symbol != " "
+}
+
+/// Put the image `data` into the first cell of an image.
+///
+/// On a layer below text, text in that cell is printed again after the image, from the cursor
+/// position that is saved before the image moves it.
+pub(crate) fn set_first_cell(cell: &mut Cell, data: &str, layer: Layer) {
+    if layer.is_below_text() && has_text(cell) {
+        let symbol = format!("\x1b7{data}\x1b8{}", cell.symbol);
+        cell.set_symbol(&symbol);
+    } else {
+        cell.set_symbol(data);
+    }
 }
 
+/// The part of `area` that an image of size `rect` covers when rendered at its top-left corner.
+pub(crate) fn covered_area(rect: Rect, area: Rect) -> Rect {
+    area.intersection(Rect::new(area.x, area.y, rect.width, rect.height))
+}
+
+/// A no-op escape sequence (save and restore the cursor).
+///
+/// States prefix their data with it in every other [Generation], so that an image that encodes to
+/// the same data still differs from the last frame, and ratatui redraws it.
+pub(crate) const REDRAW: &str = "\x1b7\x1b8";
+
 dyn_clone::clone_trait_object!(ResizeProtocol);
 
+/// Shared invalidation counter for [ResizeProtocol] states.
+///
+/// All clones share the counter, but each clone tracks which generation it has seen. States built
+/// by [crate::picker::Picker] hold a clone of the picker's generation, so that
+/// [crate::picker::Picker::invalidate_all] resets all of them at once.
+#[derive(Clone, Debug, Default)]
+pub struct Generation {
+    shared: Arc<AtomicU64>,
+    seen: u64,
+}
+
+impl Generation {
+    /// Invalidate all states holding a clone of this generation.
+    pub fn invalidate(&self) {
+        self.shared.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether [Generation::invalidate] has been called since the last [Generation::update].
+    pub fn is_stale(&self) -> bool {
+        self.shared.load(Ordering::Relaxed) != self.seen
+    }
+
+    /// Mark the current generation as seen, returning whether it was stale.
+    pub fn update(&mut self) -> bool {
+        let current = self.shared.load(Ordering::Relaxed);
+        let stale = current != self.seen;
+        self.seen = current;
+        stale
+    }
+}
+
 #[derive(Clone)]
 /// Image source for [crate::protocol::ResizeProtocol]s
 ///
 /// A `[ResizeProtocol]` needs to resize the ImageSource to its state when the available area
 /// changes. A `[Protocol]` only needs it once.
 ///
+/// The image data is shared through an [Arc], so cloning an ImageSource (or a state holding one)
+/// does not copy the pixel buffer. Several sources can share one decoded image with
+/// [ImageSource::new] given an `Arc<DynamicImage>`.
+///
 /// # Examples
 /// ```text
 /// use image::{DynamicImage, ImageBuffer, Rgb};
@@ -58,31 +284,114 @@ dyn_clone::clone_trait_object!(ResizeProtocol);
 ///
 pub struct ImageSource {
     /// The original image without resizing
-    pub image: DynamicImage,
+    pub image: Arc<DynamicImage>,
     /// The font size of the terminal
     pub font_size: FontSize,
     /// The area that the [`ImageSource::image`] covers, but not necessarily fills
     pub desired: Rect,
+    /// Identity of the image, see [ImageHash] and [ImageSource::with_id].
     pub hash: u64,
+    /// Whether encodings may be stored in a [crate::cache::DiskCache], which is only the case
+    /// when the hash is computed from the image.
+    pub(crate) persistent: bool,
+}
+
+/// How an [ImageSource] computes its [ImageSource::hash].
+///
+/// The hash tells states when the image has changed, and is part of the
+/// [crate::cache::EncodingKey].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageHash {
+    /// Hash every byte of the image. Exact, but multi-megabyte work for big images.
+    #[default]
+    Full,
+    /// Hash the dimensions, the color type, and a fixed number of samples spread over the image
+    /// buffer. Cheap regardless of the image size, but images that only differ between samples
+    /// get the same hash.
+    Sampled,
+}
+
+impl ImageHash {
+    /// Number of sampled windows for [ImageHash::Sampled].
+    const SAMPLES: usize = 1024;
+    /// Bytes per sampled window for [ImageHash::Sampled].
+    const SAMPLE_LEN: usize = 16;
+
+    fn hash(&self, image: &DynamicImage) -> u64 {
+        // Stable across Rust releases, as the hash names DiskCache files.
+        let mut state = Fnv::default();
+        let bytes = image.as_bytes();
+        match self {
+            ImageHash::Full => bytes.hash(&mut state),
+            ImageHash::Sampled => {
+                (image.width(), image.height(), image.color()).hash(&mut state);
+                let step = (bytes.len() / ImageHash::SAMPLES).max(ImageHash::SAMPLE_LEN);
+                for start in (0..bytes.len()).step_by(step) {
+                    let end = (start + ImageHash::SAMPLE_LEN).min(bytes.len());
+                    bytes[start..end].hash(&mut state);
+                }
+            }
+        }
+        state.finish()
+    }
 }
 
 impl ImageSource {
-    /// Create a new image source
-    pub fn new(image: DynamicImage, font_size: FontSize) -> ImageSource {
+    /// Create a new image source, from either a `DynamicImage` or an `Arc<DynamicImage>`.
+    ///
+    /// This hashes the entire image, see [ImageSource::with_hash] and [ImageSource::with_id] for
+    /// cheaper alternatives.
+    pub fn new(image: impl Into<Arc<DynamicImage>>, font_size: FontSize) -> ImageSource {
+        ImageSource::with_hash(image, font_size, ImageHash::Full)
+    }
+
+    /// Create a new image source, computing the hash with the given [ImageHash] method.
+    pub fn with_hash(
+        image: impl Into<Arc<DynamicImage>>,
+        font_size: FontSize,
+        image_hash: ImageHash,
+    ) -> ImageSource {
+        let image = image.into();
+        let hash = image_hash.hash(&image);
+        ImageSource::from_parts(image, font_size, hash, true)
+    }
+
+    /// Create a new image source with an explicit identity, without hashing the image at all.
+    ///
+    /// The `id` must change whenever the image changes, e.g. a frame counter for video frames.
+    /// It is mixed with the image dimensions, so that it doesn't collide with hashes of
+    /// [ImageHash], nor with the same id for an image of another size. Ids are only meaningful
+    /// within one run, so these sources are never stored in a [crate::cache::DiskCache].
+    pub fn with_id(
+        image: impl Into<Arc<DynamicImage>>,
+        font_size: FontSize,
+        id: u64,
+    ) -> ImageSource {
+        let image = image.into();
+        let mut state = Fnv::default();
+        state.write(b"with_id");
+        (id, image.width(), image.height(), image.color()).hash(&mut state);
+        ImageSource::from_parts(image, font_size, state.finish(), false)
+    }
+
+    fn from_parts(
+        image: Arc<DynamicImage>,
+        font_size: FontSize,
+        hash: u64,
+        persistent: bool,
+    ) -> ImageSource {
         let desired =
             ImageSource::round_pixel_size_to_cells(image.width(), image.height(), font_size);
 
-        let mut state = DefaultHasher::new();
-        image.as_bytes().hash(&mut state);
-        let hash = state.finish();
-
         ImageSource {
             image,
             font_size,
             desired,
             hash,
+            persistent,
         }
     }
+
     /// Round an image pixel size to the nearest matching cell size, given a font size.
     fn round_pixel_size_to_cells(
         img_width: u32,
@@ -94,3 +403,216 @@ impl ImageSource {
         Rect::new(0, 0, width, height)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::{
+        halfblocks::{FixedHalfblocks, HalfblocksState},
+        iterm::FixedIterm,
+        kitty::KittyPlacement,
+        Generation, ImageHash, ImageSource, Protocol, ProtocolFactory, ResizeProtocol, REDRAW,
+    };
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Layer, Resize, Result,
+    };
+
+    fn image(width: u32, height: u32) -> DynamicImage {
+        RgbImage::from_pixel(width, height, Rgb([255, 0, 0])).into()
+    }
+
+    #[test]
+    fn with_id() {
+        let source = ImageSource::with_id(image(10, 10), (1, 1), 1);
+        assert_eq!(
+            source.hash,
+            ImageSource::with_id(image(10, 10), (1, 1), 1).hash
+        );
+        assert_ne!(
+            source.hash,
+            ImageSource::with_id(image(10, 10), (1, 1), 2).hash
+        );
+        // Same id for another image size.
+        assert_ne!(
+            source.hash,
+            ImageSource::with_id(image(10, 20), (1, 1), 1).hash
+        );
+        assert_ne!(1, source.hash);
+        assert!(!source.persistent);
+        assert!(ImageSource::new(image(10, 10), (1, 1)).persistent);
+    }
+
+    #[test]
+    fn sampled_hash() {
+        let hash = |image: &DynamicImage| ImageHash::Sampled.hash(image);
+        let mut changed = image(100, 100).into_rgb8();
+        assert_eq!(hash(&image(100, 100)), hash(&changed.clone().into()));
+        // The first pixel is always sampled.
+        changed.put_pixel(0, 0, Rgb([0, 0, 0]));
+        assert_ne!(hash(&image(100, 100)), hash(&changed.into()));
+        // Same bytes, other dimensions.
+        assert_ne!(hash(&image(100, 100)), hash(&image(50, 200)));
+        assert_ne!(
+            hash(&image(100, 100)),
+            ImageHash::Full.hash(&image(100, 100))
+        );
+    }
+
+    #[test]
+    fn generation() {
+        let mut a = Generation::default();
+        let mut b = a.clone();
+        assert!(!a.is_stale());
+        b.invalidate();
+        assert!(a.is_stale() && b.is_stale());
+        assert!(a.update());
+        assert!(!a.is_stale() && b.is_stale());
+        assert!(!a.update());
+        assert!(b.update());
+    }
+
+    #[test]
+    fn invalidate_all() {
+        let area = Rect::new(0, 0, 10, 10);
+        let mut picker = Picker::new((1, 1), ProtocolType::Kitty, None).unwrap();
+        let mut kitty = picker.new_state(image(10, 10));
+        picker.set(ProtocolType::Halfblocks).unwrap();
+        let mut halfblocks = picker.new_state(image(10, 10));
+        for state in [&mut kitty, &mut halfblocks] {
+            state.render(&Resize::Fit, None, area, &mut Buffer::empty(area));
+            assert_eq!(None, state.needs_resize(&Resize::Fit, area));
+        }
+
+        picker.invalidate_all();
+        for state in [&kitty, &halfblocks] {
+            assert!(state.needs_resize(&Resize::Fit, area).is_some());
+        }
+    }
+
+    #[test]
+    fn invalidate_all_redraws() {
+        fn render(state: &mut Box<dyn ResizeProtocol>, area: Rect) -> Buffer {
+            let mut buf = Buffer::empty(area);
+            state.render(&Resize::Fit, None, area, &mut buf);
+            buf
+        }
+
+        let area = Rect::new(0, 0, 4, 4);
+        let types = [
+            ProtocolType::Halfblocks,
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+            ProtocolType::Sixel,
+            ProtocolType::Iterm,
+        ];
+        for protocol_type in types {
+            let mut picker = Picker::new((2, 2), protocol_type, None).unwrap();
+            let mut state = picker.new_state(image(8, 8));
+            let first = render(&mut state, area);
+            let second = render(&mut state, area);
+            assert!(first.diff(&second).is_empty(), "{protocol_type:?}");
+            assert!(!state.stats().transmitted, "{protocol_type:?}");
+
+            picker.invalidate_all();
+            let third = render(&mut state, area);
+            let diff = second.diff(&third);
+            assert!(state.stats().transmitted, "{protocol_type:?}");
+            match protocol_type {
+                // Every cell is flipped.
+                ProtocolType::Halfblocks => assert_eq!(16, diff.len()),
+                _ => {
+                    let (x, y, cell) = diff[0];
+                    assert_eq!((0, 0), (x, y), "{protocol_type:?}");
+                    let data = &second.get(0, 0).symbol;
+                    assert_eq!(format!("{REDRAW}{data}"), cell.symbol, "{protocol_type:?}");
+                }
+            }
+
+            let fourth = render(&mut state, area);
+            assert!(third.diff(&fourth).is_empty(), "{protocol_type:?}");
+            assert!(!state.stats().transmitted, "{protocol_type:?}");
+        }
+    }
+
+    #[test]
+    fn below_text_keeps_first_cell_text() {
+        let area = Rect::new(0, 0, 4, 4);
+        let source = ImageSource::new(image(8, 8), (2, 2));
+        let fixed = FixedIterm::from_source(&source, Resize::Fit, None, area).unwrap();
+        let mut buf = Buffer::empty(area);
+        buf.get_mut(0, 0).set_symbol("a");
+        fixed.render_layer(area, &mut buf, Layer::BELOW_TEXT);
+        // The image, then the text from the same position.
+        assert_eq!(format!("\x1b7{}\x1b8a", fixed.data), buf.get(0, 0).symbol);
+    }
+
+    #[test]
+    fn clear() {
+        let area = Rect::new(1, 1, 4, 4);
+        let types = [
+            ProtocolType::Halfblocks,
+            #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+            ProtocolType::Sixel,
+            ProtocolType::Kitty,
+            ProtocolType::Iterm,
+        ];
+        for protocol_type in types {
+            let mut picker = Picker::new((2, 2), protocol_type, None).unwrap();
+            picker.set_kitty_placement(KittyPlacement::Placeholders);
+            let blank =
+                |buf: &Buffer| (1..5).all(|y| (1..5).all(|x| buf.get(x, y) == &Default::default()));
+
+            let mut state = picker.new_state(image(8, 8));
+            let mut buf = Buffer::empty(Rect::new(0, 0, 6, 6));
+            state.render(&Resize::Fit, None, area, &mut buf);
+            assert!(!blank(&buf), "{protocol_type:?}");
+            state.clear(&mut buf);
+            assert!(blank(&buf), "{protocol_type:?}");
+
+            let fixed = picker
+                .new_static_fit(image(8, 8), area, Resize::Fit)
+                .unwrap();
+            let mut buf = Buffer::empty(Rect::new(0, 0, 6, 6));
+            fixed.render(area, &mut buf);
+            assert!(!blank(&buf), "{protocol_type:?}");
+            fixed.clear(area, &mut buf);
+            assert!(blank(&buf), "{protocol_type:?}");
+        }
+    }
+
+    struct Halfblocks;
+
+    impl ProtocolFactory for Halfblocks {
+        fn new_static(
+            &self,
+            source: &ImageSource,
+            resize: Resize,
+            background_color: Option<Rgb<u8>>,
+            area: Rect,
+        ) -> Result<Box<dyn Protocol>> {
+            let fixed = FixedHalfblocks::from_source(source, resize, background_color, area)?;
+            Ok(Box::new(fixed))
+        }
+        fn new_state(&self, source: ImageSource) -> Box<dyn ResizeProtocol> {
+            Box::new(HalfblocksState::new(source))
+        }
+    }
+
+    #[test]
+    fn register() {
+        assert!(Picker::new((1, 1), ProtocolType::Custom(0), None).is_err());
+        let mut picker = Picker::new((1, 1), ProtocolType::Halfblocks, None).unwrap();
+        assert!(picker.set(ProtocolType::Custom(0)).is_err());
+        for index in 0..=255 {
+            assert_eq!(
+                ProtocolType::Custom(index),
+                picker.register(Halfblocks).unwrap()
+            );
+        }
+        assert!(picker.register(Halfblocks).is_err());
+        assert!(picker.set(ProtocolType::Custom(255)).is_ok());
+    }
+}
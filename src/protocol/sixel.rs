@@ -1,24 +1,44 @@
 //! Sixel protocol implementations.
 //! Uses [`sixel-bytes`] to draw image pixels, if the terminal [supports] the [Sixel] protocol.
-//! Needs the `sixel` feature.
+//! Needs the `sixel` feature, or the `sixel-native` feature for a pure Rust encoder without
+//! libsixel.
 //!
 //! [`sixel-bytes`]: https://github.com/benjajaja/sixel-bytes
 //! [supports]: https://arewesixelyet.com
 //! [Sixel]: https://en.wikipedia.org/wiki/Sixel
+#[cfg(feature = "sixel")]
+use color_quant::NeuQuant;
 use image::{DynamicImage, Rgb};
 use ratatui::{buffer::Buffer, layout::Rect};
-use sixel_bytes::{sixel_string, DiffusionMethod, PixelFormat, SixelError};
-use std::cmp::min;
-use std::io;
+#[cfg(feature = "sixel")]
+use sixel_bytes::{sixel_string, DiffusionMethod, PixelFormat};
+#[cfg(feature = "sixel")]
+use std::borrow::Cow;
+use std::{cmp::min, sync::Arc};
 
-use super::{Protocol, ResizeProtocol};
-use crate::{ImageSource, Resize, Result};
+use super::{
+    clear_area, covered_area, has_text, set_first_cell, Generation, Protocol, ResizeProtocol,
+    REDRAW,
+};
+#[cfg(feature = "sixel")]
+use crate::quality::{SixelDiffusion, SixelSpeed};
+use crate::{
+    cache::{self, Encoded, EncodingCache, EncodingKey},
+    picker::ProtocolType,
+    quality::{Quality, SixelOptions},
+    stats::{self, RenderStats, Telemetry},
+    Error, ImageSource, Layer, Resize, Result,
+};
+
+mod dither;
+mod encoder;
 
 // Fixed sixel protocol
 #[derive(Clone, Default)]
 pub struct FixedSixel {
     pub data: String,
     pub rect: Rect,
+    stats: RenderStats,
 }
 
 impl FixedSixel {
@@ -28,43 +48,172 @@ impl FixedSixel {
         background_color: Option<Rgb<u8>>,
         area: Rect,
     ) -> Result<Self> {
-        let (img, rect) = resize
-            .resize(source, Rect::default(), area, background_color, false)
-            .unwrap_or_else(|| (source.image.clone(), source.desired));
+        Self::from_source_cached(
+            source,
+            resize,
+            background_color,
+            area,
+            Quality::default(),
+            SixelOptions::default(),
+            None,
+        )
+    }
+
+    pub(crate) fn from_source_cached(
+        source: &ImageSource,
+        resize: Resize,
+        background_color: Option<Rgb<u8>>,
+        area: Rect,
+        quality: Quality,
+        options: SixelOptions,
+        cache: Option<&EncodingCache>,
+    ) -> Result<Self> {
+        let rect = resize
+            .needs_resize(source, Rect::default(), area, false)
+            .unwrap_or(source.desired);
+        let key = EncodingKey::new(source, rect, &resize, background_color, ProtocolType::Sixel)
+            .quality(quality)
+            .sixel(options);
+        let mut stats = RenderStats {
+            transmitted: true,
+            ..RenderStats::default()
+        };
+        let mut fixed = cache::get_or_encode(cache, key, || {
+            let (img, rect) = stats::timed(&mut stats.resize_time, || {
+                resize
+                    .needs_resize(source, Rect::default(), area, false)
+                    .map(|rect| {
+                        let img = resize_to(&resize, source, rect, background_color, &options);
+                        (img, rect)
+                    })
+                    .unwrap_or_else(|| ((*source.image).clone(), source.desired))
+            });
+
+            let data = stats::timed(&mut stats.encode_time, || {
+                encode_with_options(img, &quality, &options)
+            })?;
+            Ok(Self {
+                data,
+                rect,
+                stats: RenderStats::default(),
+            })
+        })?;
+        stats.bytes = fixed.data.len();
+        fixed.stats = stats;
+        Ok(fixed)
+    }
+}
 
-        let data = encode(img)?;
-        Ok(Self { data, rect })
+/// Resize to `rect`, padded with transparent pixels if `options` leave pixels unpainted.
+fn resize_to(
+    resize: &Resize,
+    source: &ImageSource,
+    rect: Rect,
+    background_color: Option<Rgb<u8>>,
+    options: &SixelOptions,
+) -> DynamicImage {
+    match options.alpha_threshold {
+        Some(_) => resize.resize_to_padded(source, rect, background_color),
+        None => resize.resize_to(source, rect, background_color),
     }
 }
 
-// TODO: change E to sixel_rs::status::Error and map when calling
 pub fn encode(img: DynamicImage) -> Result<String> {
+    encode_with_quality(img, &Quality::FULL)
+}
+
+/// Encode with the palette size of `quality`, reducing it further to fit its frame budget.
+pub fn encode_with_quality(img: DynamicImage, quality: &Quality) -> Result<String> {
+    encode_with_options(img, quality, &SixelOptions::default())
+}
+
+/// Encode with `options` and the palette size of `quality`, reducing it further to fit its frame
+/// budget.
+#[cfg(feature = "sixel")]
+pub fn encode_with_options(
+    img: DynamicImage,
+    quality: &Quality,
+    options: &SixelOptions,
+) -> Result<String> {
+    if options.alpha_threshold.is_some() {
+        return encode_native(img, quality, options);
+    }
     let (w, h) = (img.width(), img.height());
     let img_rgba8 = img.to_rgba8();
-    let bytes = img_rgba8.as_raw();
-
-    let data = sixel_string(
-        bytes,
-        w as _,
-        h as _,
-        PixelFormat::RGBA8888,
-        DiffusionMethod::Stucki,
-    )
-    .map_err(sixel_err)?;
-    Ok(data)
+
+    quality.encode_colors_within_budget(options, |colors| {
+        let mut bytes = Cow::Borrowed(img_rgba8.as_raw());
+        let mut diffusion = match options.diffusion {
+            SixelDiffusion::None | SixelDiffusion::Ordered => DiffusionMethod::None,
+            SixelDiffusion::Atkinson => DiffusionMethod::Atkinson,
+            SixelDiffusion::FloydSteinberg => DiffusionMethod::FS,
+            SixelDiffusion::Stucki => DiffusionMethod::Stucki,
+            SixelDiffusion::Burkes => DiffusionMethod::Burkes,
+        };
+        if colors < 256 || options.diffusion == SixelDiffusion::Ordered {
+            // libsixel always builds a palette of up to 256 colors and has no ordered dithering,
+            // so map to the palette first, and let libsixel keep the colors as they are.
+            let sample_factor = match options.speed {
+                SixelSpeed::Fast => 30,
+                SixelSpeed::Balanced => 10,
+                SixelSpeed::Best => 1,
+            };
+            let quant = NeuQuant::new(sample_factor, colors as usize, &bytes);
+            let palette: Vec<[u8; 3]> = quant
+                .color_map_rgb()
+                .chunks_exact(3)
+                .map(|color| [color[0], color[1], color[2]])
+                .collect();
+            let pixels: Vec<Option<[u8; 3]>> = bytes
+                .chunks_exact(4)
+                .map(|pixel| Some([pixel[0], pixel[1], pixel[2]]))
+                .collect();
+            let indices = dither::dither(&pixels, w as usize, &palette, options.diffusion);
+            for (pixel, index) in bytes.to_mut().chunks_exact_mut(4).zip(indices) {
+                if let Some(index) = index {
+                    pixel[..3].copy_from_slice(&palette[index]);
+                }
+            }
+            diffusion = DiffusionMethod::None;
+        }
+        let data = sixel_string(&bytes, w as _, h as _, PixelFormat::RGBA8888, diffusion)?;
+        Ok(data)
+    })
+}
+
+/// Encode with `options` and the palette size of `quality`, reducing it further to fit its frame
+/// budget.
+#[cfg(not(feature = "sixel"))]
+pub fn encode_with_options(
+    img: DynamicImage,
+    quality: &Quality,
+    options: &SixelOptions,
+) -> Result<String> {
+    encode_native(img, quality, options)
 }
 
-fn sixel_err(err: SixelError) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, format!("{err:?}"))
+/// Encode with the native encoder, see [encode_with_options].
+fn encode_native(img: DynamicImage, quality: &Quality, options: &SixelOptions) -> Result<String> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let img_rgba8 = img.to_rgba8();
+    quality.encode_colors_within_budget(options, |colors| {
+        Ok(encoder::encode(img_rgba8.as_raw(), w, h, colors, options))
+    })
 }
 
 impl Protocol for FixedSixel {
     fn render(&self, area: Rect, buf: &mut Buffer) {
-        render(self.rect, &self.data, area, buf, false)
+        self.render_layer(area, buf, Layer::default());
+    }
+    fn render_layer(&self, area: Rect, buf: &mut Buffer, layer: Layer) {
+        render(self.rect, &self.data, area, buf, false, layer)
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
     }
 }
 
-fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool) {
+fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool, layer: Layer) {
     let render_area = match render_area(rect, area, overdraw) {
         None => {
             // If we render out of area, then the buffer will attempt to write regular text (or
@@ -89,8 +238,8 @@ fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool)
         Some(r) => r,
     };
 
-    buf.get_mut(render_area.left(), render_area.top())
-        .set_symbol(data);
+    let cell = buf.get_mut(render_area.left(), render_area.top());
+    set_first_cell(cell, data, layer);
     let mut skip_first = false;
 
     // Skip entire area
@@ -100,7 +249,12 @@ fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool)
                 skip_first = true;
                 continue;
             }
-            buf.get_mut(x, y).set_skip(true);
+            let cell = buf.get_mut(x, y);
+            // Let the terminal draw text over the image.
+            if layer.is_below_text() && has_text(cell) {
+                continue;
+            }
+            cell.set_skip(true);
         }
     }
 }
@@ -121,11 +275,41 @@ fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
     Some(Rect::new(area.x, area.y, rect.width, rect.height))
 }
 
+impl Encoded for FixedSixel {
+    fn byte_size(&self) -> usize {
+        self.data.len()
+    }
+    fn to_disk(&self) -> Option<&str> {
+        Some(&self.data)
+    }
+    fn from_disk(data: String, key: &EncodingKey) -> Option<Self> {
+        Some(FixedSixel {
+            data,
+            rect: key.rect,
+            stats: RenderStats::default(),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct SixelState {
     source: ImageSource,
     current: FixedSixel,
     hash: u64,
+    cache: Option<EncodingCache>,
+    generation: Generation,
+    /// The area covered at the last render
+    area: Rect,
+    telemetry: Telemetry,
+    stats: RenderStats,
+    /// Whether `current` has not been rendered yet
+    fresh: bool,
+    /// Whether `current` is prefixed with [REDRAW], toggled on every new generation
+    redraw: bool,
+    layer: Layer,
+    quality: Quality,
+    options: SixelOptions,
+    error: Option<Arc<Error>>,
 }
 
 impl SixelState {
@@ -134,45 +318,147 @@ impl SixelState {
             source,
             current: FixedSixel::default(),
             hash: u64::default(),
+            cache: None,
+            generation: Generation::default(),
+            area: Rect::default(),
+            telemetry: Telemetry::default(),
+            stats: RenderStats::default(),
+            fresh: false,
+            redraw: false,
+            layer: Layer::default(),
+            quality: Quality::default(),
+            options: SixelOptions::default(),
+            error: None,
         }
     }
+
+    /// Share encodings through an [EncodingCache].
+    pub fn cache(mut self, cache: Option<EncodingCache>) -> SixelState {
+        self.cache = cache;
+        self
+    }
+
+    /// Invalidate together with all other states holding a clone of `generation`.
+    pub fn generation(mut self, generation: Generation) -> SixelState {
+        self.generation = generation;
+        self
+    }
+
+    /// Add [RenderStats] up in a shared [Telemetry].
+    pub fn telemetry(mut self, telemetry: Telemetry) -> SixelState {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Encode with the palette size and budget of `quality`.
+    pub fn quality(mut self, quality: Quality) -> SixelState {
+        self.quality = quality;
+        self
+    }
+
+    /// Encode with the diffusion, palette size and speed of `options`.
+    pub fn options(mut self, options: SixelOptions) -> SixelState {
+        self.options = options;
+        self
+    }
 }
 
 impl ResizeProtocol for SixelState {
     fn rect(&self) -> Rect {
         self.current.rect
     }
-    fn render(
-        &mut self,
-        resize: &Resize,
-        background_color: Option<Rgb<u8>>,
-        area: Rect,
-        buf: &mut Buffer,
-    ) {
+    fn source(&self) -> Option<&ImageSource> {
+        Some(&self.source)
+    }
+    fn into_source(self: Box<Self>) -> Option<ImageSource> {
+        Some(self.source)
+    }
+    fn needs_resize(&self, resize: &Resize, area: Rect) -> Option<Rect> {
+        let force = self.source.hash != self.hash || self.generation.is_stale();
+        resize.needs_resize(&self.source, self.current.rect, area, force)
+    }
+    fn resize_encode(&mut self, resize: &Resize, background_color: Option<Rgb<u8>>, area: Rect) {
         if area.width == 0 || area.height == 0 {
             return;
         }
 
-        let force = self.source.hash != self.hash;
-        if let Some((img, rect)) = resize.resize(
-            &self.source,
-            self.current.rect,
-            area,
-            background_color,
-            force,
-        ) {
-            match encode(img) {
-                Ok(data) => {
-                    let current = FixedSixel { data, rect };
+        let stale = self.generation.update();
+        self.redraw ^= stale;
+        let force = stale || self.source.hash != self.hash;
+        if let Some(rect) = resize.needs_resize(&self.source, self.current.rect, area, force) {
+            let key = EncodingKey::new(
+                &self.source,
+                rect,
+                resize,
+                background_color,
+                ProtocolType::Sixel,
+            )
+            .quality(self.quality)
+            .sixel(self.options);
+            let mut stats = RenderStats::default();
+            let encoded = cache::get_or_encode(self.cache.as_ref(), key, || {
+                let img = stats::timed(&mut stats.resize_time, || {
+                    resize_to(resize, &self.source, rect, background_color, &self.options)
+                });
+                let data = stats::timed(&mut stats.encode_time, || {
+                    encode_with_options(img, &self.quality, &self.options)
+                })?;
+                Ok(FixedSixel {
+                    data,
+                    rect,
+                    stats: RenderStats::default(),
+                })
+            });
+            match encoded {
+                Ok(mut current) => {
+                    if self.redraw {
+                        current.data.insert_str(0, REDRAW);
+                    }
+                    // The same data in the same cell is not drawn again.
+                    self.fresh |= current.data != self.current.data;
                     self.current = current;
                     self.hash = self.source.hash;
+                    self.stats = stats;
+                    self.telemetry.record_encode(&stats);
+                    self.error = None;
                 }
-                Err(_err) => {
-                    // TODO: save err in struct and expose in trait?
-                }
+                Err(err) => self.error = Some(Arc::new(err)),
             }
         }
-
-        render(self.current.rect, &self.current.data, area, buf, true);
+    }
+    fn render_current(&mut self, area: Rect, buf: &mut Buffer) {
+        self.area = covered_area(self.current.rect, area);
+        render(
+            self.current.rect,
+            &self.current.data,
+            area,
+            buf,
+            true,
+            self.layer,
+        );
+        self.stats.transmitted = std::mem::take(&mut self.fresh);
+        self.stats.bytes = if self.stats.transmitted {
+            self.current.data.len()
+        } else {
+            0
+        };
+        self.telemetry.record_render(&self.stats);
+    }
+    fn reset(&mut self) {
+        self.current = FixedSixel::default();
+        self.hash = u64::default();
+    }
+    fn clear(&mut self, buf: &mut Buffer) {
+        clear_area(self.area, buf);
+        self.area = Rect::default();
+    }
+    fn stats(&self) -> RenderStats {
+        self.stats
+    }
+    fn last_error(&self) -> Option<&Error> {
+        self.error.as_deref()
+    }
+    fn set_layer(&mut self, layer: Layer) {
+        self.layer = layer;
     }
 }
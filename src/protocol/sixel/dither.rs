@@ -0,0 +1,144 @@
+//! Mapping pixels to a palette, with the diffusion of [SixelDiffusion].
+use std::collections::HashMap;
+
+use crate::quality::SixelDiffusion;
+
+/// The neighbours `(dx, dy, weight)` that get a share of the error, and the sum of all shares.
+fn kernel(diffusion: SixelDiffusion) -> (&'static [(isize, usize, i32)], i32) {
+    match diffusion {
+        SixelDiffusion::None | SixelDiffusion::Ordered => (&[], 1),
+        SixelDiffusion::Atkinson => (
+            &[
+                (1, 0, 1),
+                (2, 0, 1),
+                (-1, 1, 1),
+                (0, 1, 1),
+                (1, 1, 1),
+                (0, 2, 1),
+            ],
+            8,
+        ),
+        SixelDiffusion::FloydSteinberg => (&[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)], 16),
+        SixelDiffusion::Stucki => (
+            &[
+                (1, 0, 8),
+                (2, 0, 4),
+                (-2, 1, 2),
+                (-1, 1, 4),
+                (0, 1, 8),
+                (1, 1, 4),
+                (2, 1, 2),
+                (-2, 2, 1),
+                (-1, 2, 2),
+                (0, 2, 4),
+                (1, 2, 2),
+                (2, 2, 1),
+            ],
+            42,
+        ),
+        SixelDiffusion::Burkes => (
+            &[
+                (1, 0, 8),
+                (2, 0, 4),
+                (-2, 1, 2),
+                (-1, 1, 4),
+                (0, 1, 8),
+                (1, 1, 4),
+                (2, 1, 2),
+            ],
+            32,
+        ),
+    }
+}
+
+static BAYER: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+pub(super) fn nearest(palette: &[[u8; 3]], color: [u8; 3]) -> usize {
+    let distance = |entry: &[u8; 3]| -> i32 {
+        (0..3)
+            .map(|channel| {
+                let d = entry[channel] as i32 - color[channel] as i32;
+                d * d
+            })
+            .sum()
+    };
+    (0..palette.len())
+        .min_by_key(|index| distance(&palette[*index]))
+        .unwrap_or(0)
+}
+
+/// Map the pixels of an image `width` pixels wide to indices into `palette`.
+///
+/// Unpainted pixels (`None`) stay unpainted, and get no error.
+pub(super) fn dither(
+    pixels: &[Option<[u8; 3]>],
+    width: usize,
+    palette: &[[u8; 3]],
+    diffusion: SixelDiffusion,
+) -> Vec<Option<usize>> {
+    let (kernel, divisor) = kernel(diffusion);
+    // The distance between palette colors, roughly, if they were spread evenly over the cube.
+    let spread = (256.0 / (palette.len() as f64).cbrt().max(1.0)) as i32;
+    let mut errors = vec![[0i32; 3]; if kernel.is_empty() { 0 } else { pixels.len() }];
+    let mut mapped = HashMap::new();
+    let mut indices = Vec::with_capacity(pixels.len());
+    for (i, pixel) in pixels.iter().enumerate() {
+        let Some(pixel) = pixel else {
+            indices.push(None);
+            continue;
+        };
+        let (x, y) = (i % width, i / width);
+        let offset = |channel: usize| match diffusion {
+            SixelDiffusion::Ordered => (BAYER[y % 4][x % 4] * 2 - 15) * spread / 32,
+            _ if kernel.is_empty() => 0,
+            _ => errors[i][channel] / divisor,
+        };
+        let color = [0, 1, 2].map(|c| (pixel[c] as i32 + offset(c)).clamp(0, 255) as u8);
+        let index = *mapped
+            .entry(color)
+            .or_insert_with(|| nearest(palette, color));
+        indices.push(Some(index));
+
+        let error = [0, 1, 2].map(|c| color[c] as i32 - palette[index][c] as i32);
+        for (dx, dy, weight) in kernel {
+            let nx = x as isize + dx;
+            if nx < 0 || nx as usize >= width {
+                continue;
+            }
+            let j = (y + dy) * width + nx as usize;
+            if let Some(neighbour) = errors.get_mut(j) {
+                for c in 0..3 {
+                    neighbour[c] += error[c] * weight;
+                }
+            }
+        }
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither_gray() {
+        // Mid gray between black and white.
+        let pixels = vec![Some([128, 128, 128]); 16];
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        let count_white =
+            |indices: Vec<Option<usize>>| indices.iter().filter(|i| **i == Some(1)).count();
+        assert_eq!(
+            16,
+            count_white(dither(&pixels, 4, &palette, SixelDiffusion::None))
+        );
+        for diffusion in [
+            SixelDiffusion::FloydSteinberg,
+            SixelDiffusion::Stucki,
+            SixelDiffusion::Burkes,
+            SixelDiffusion::Ordered,
+        ] {
+            let white = count_white(dither(&pixels, 4, &palette, diffusion));
+            assert!((6..=10).contains(&white), "{diffusion:?}: {white}");
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Native sixel encoder, used when libsixel (the `sixel` feature) isn't enabled, or for
+//! transparent sixels.
+//!
+//! The palette is built with median cut, pixels are mapped to the palette with the diffusion of
+//! [SixelOptions], and the bands of six pixel rows are written with run-length compression.
+//! Transparent pixels are left unpainted.
+use std::{collections::HashMap, fmt::Write};
+
+use super::dither::{dither, nearest};
+use crate::quality::{SixelOptions, SixelSpeed};
+
+/// A box of the RGB color space, with the histogram entries that fall into it.
+struct ColorBox {
+    entries: Vec<([u8; 3], u32)>,
+}
+
+impl ColorBox {
+    /// The channel with the widest range, and that range.
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let (min, max) = self.entries.iter().fold((u8::MAX, 0), |(min, max), entry| {
+                    (min.min(entry.0[channel]), max.max(entry.0[channel]))
+                });
+                (channel, max.saturating_sub(min))
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    }
+
+    /// Split at the median pixel along the widest channel.
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.entries.sort_unstable_by_key(|entry| entry.0[channel]);
+        let half = self.entries.iter().map(|entry| entry.1 as u64).sum::<u64>() / 2;
+        let mut count = 0;
+        let mut median = 1;
+        for (index, entry) in self.entries.iter().enumerate() {
+            count += entry.1 as u64;
+            if count >= half {
+                median = index + 1;
+                break;
+            }
+        }
+        // Both halves keep at least one color.
+        let median = median.clamp(1, self.entries.len() - 1);
+        let rest = self.entries.split_off(median);
+        (self, ColorBox { entries: rest })
+    }
+
+    /// The average color, weighted by pixel count.
+    fn average(&self) -> [u8; 3] {
+        let mut sum = [0u64; 3];
+        let mut total = 0u64;
+        for (color, count) in &self.entries {
+            for channel in 0..3 {
+                sum[channel] += color[channel] as u64 * *count as u64;
+            }
+            total += *count as u64;
+        }
+        sum.map(|sum| (sum / total.max(1)) as u8)
+    }
+}
+
+/// Passes of [refine] with [SixelSpeed::Best].
+const REFINE_PASSES: usize = 2;
+
+/// Build a palette of at most `colors` colors with median cut.
+fn median_cut(pixels: &[[u8; 3]], colors: usize, speed: SixelSpeed) -> Vec<[u8; 3]> {
+    // Fewer distinct colors to sort and split.
+    let mask = match speed {
+        SixelSpeed::Fast => 0xF8,
+        SixelSpeed::Balanced | SixelSpeed::Best => 0xFF,
+    };
+    let mut histogram: HashMap<[u8; 3], u32> = HashMap::new();
+    for pixel in pixels {
+        *histogram.entry(pixel.map(|c| c & mask)).or_default() += 1;
+    }
+    // Sorted, so that the same pixels always get the same palette.
+    let mut entries: Vec<([u8; 3], u32)> = histogram.into_iter().collect();
+    entries.sort_unstable();
+    let mut boxes = vec![ColorBox {
+        entries: entries.clone(),
+    }];
+    while boxes.len() < colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, color_box)| color_box.entries.len() > 1)
+            .max_by_key(|(_, color_box)| color_box.widest_channel().1);
+        let Some((index, _)) = widest else {
+            // Every color has a palette entry of its own.
+            break;
+        };
+        let (a, b) = boxes.swap_remove(index).split();
+        boxes.push(a);
+        boxes.push(b);
+    }
+    let mut palette: Vec<[u8; 3]> = boxes.iter().map(ColorBox::average).collect();
+    if speed == SixelSpeed::Best {
+        for _ in 0..REFINE_PASSES {
+            palette = refine(&palette, &entries);
+        }
+    }
+    palette
+}
+
+/// Move every palette color to the average of the colors that are nearest to it (k-means).
+fn refine(palette: &[[u8; 3]], entries: &[([u8; 3], u32)]) -> Vec<[u8; 3]> {
+    let mut boxes: Vec<ColorBox> = palette
+        .iter()
+        .map(|_| ColorBox { entries: vec![] })
+        .collect();
+    for entry in entries {
+        boxes[nearest(palette, entry.0)].entries.push(*entry);
+    }
+    boxes
+        .iter()
+        .zip(palette)
+        .map(|(color_box, color)| match color_box.entries.is_empty() {
+            true => *color,
+            false => color_box.average(),
+        })
+        .collect()
+}
+
+/// Encode RGBA8 pixels as a sixel string with a palette of at most `colors` colors.
+pub(super) fn encode(
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+    colors: u16,
+    options: &SixelOptions,
+) -> String {
+    let threshold = options.alpha_threshold;
+    let pixels: Vec<Option<[u8; 3]>> = rgba
+        .chunks_exact(4)
+        .map(|pixel| match threshold {
+            Some(threshold) if pixel[3] < threshold => None,
+            _ => Some([pixel[0], pixel[1], pixel[2]]),
+        })
+        .collect();
+    let painted: Vec<[u8; 3]> = pixels.iter().flatten().copied().collect();
+    let palette = median_cut(&painted, (colors as usize).clamp(1, 256), options.speed);
+    let indexed = dither(&pixels, width, &palette, options.diffusion);
+
+    // P2=1 leaves the pixels without any color bits as they are.
+    let mut out = match threshold {
+        Some(_) => format!("\x1bP0;1q\"1;1;{width};{height}"),
+        None => format!("\x1bPq\"1;1;{width};{height}"),
+    };
+    for (index, [r, g, b]) in palette.iter().enumerate() {
+        // Sixel colors are percentages.
+        let percent = |channel: &u8| (*channel as u32 * 100 + 127) / 255;
+        let _ = write!(
+            out,
+            "#{index};2;{};{};{}",
+            percent(r),
+            percent(g),
+            percent(b)
+        );
+    }
+
+    // The six-pixel columns of each color in the band, as sixel characters.
+    let mut band: Vec<Option<Vec<u8>>> = vec![None; palette.len()];
+    for top in (0..height).step_by(6) {
+        for y in top..(top + 6).min(height) {
+            let bit = 1 << (y - top);
+            for x in 0..width {
+                if let Some(index) = indexed[y * width + x] {
+                    band[index].get_or_insert_with(|| vec![0; width])[x] |= bit;
+                }
+            }
+        }
+        let mut first = true;
+        for (index, sixels) in band.iter_mut().enumerate() {
+            let Some(sixels) = sixels.take() else {
+                continue;
+            };
+            if !first {
+                // Back to the start of the band, to paint the next color over it.
+                out.push('$');
+            }
+            first = false;
+            let _ = write!(out, "#{index}");
+            write_runs(&mut out, &sixels);
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+/// Write the sixels with run-length compression, dropping the empty ones at the end.
+fn write_runs(out: &mut String, sixels: &[u8]) {
+    let end = sixels
+        .iter()
+        .rposition(|bits| *bits != 0)
+        .map_or(0, |x| x + 1);
+    let mut x = 0;
+    while x < end {
+        let bits = sixels[x];
+        let run = sixels[x..end].iter().take_while(|b| **b == bits).count();
+        let char = (b'?' + bits) as char;
+        if run > 3 {
+            let _ = write!(out, "!{run}{char}");
+        } else {
+            out.extend(std::iter::repeat(char).take(run));
+        }
+        x += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quality::SixelDiffusion;
+
+    #[test]
+    fn encode_bands() {
+        // 8 red pixels on top of 8 blue ones, 5 columns each.
+        let mut rgba = Vec::new();
+        for y in 0..16 {
+            let color = if y < 8 {
+                [255, 0, 0, 255]
+            } else {
+                [0, 0, 255, 255]
+            };
+            for _ in 0..5 {
+                rgba.extend(color);
+            }
+        }
+        let options = SixelOptions {
+            diffusion: SixelDiffusion::None,
+            ..SixelOptions::default()
+        };
+        let data = encode(&rgba, 5, 16, 16, &options);
+        assert!(data.starts_with("\x1bPq\"1;1;5;16"));
+        assert!(data.ends_with("-\x1b\\"));
+        let palette = median_cut(&[[255, 0, 0], [0, 0, 255]], 16, SixelSpeed::Balanced);
+        let red = palette.iter().position(|c| *c == [255, 0, 0]).unwrap();
+        let blue = palette.iter().position(|c| *c == [0, 0, 255]).unwrap();
+        assert!(data.contains(&format!("#{red};2;100;0;0")));
+        assert!(data.contains(&format!("#{blue};2;0;0;100")));
+        // Rows 0-5 are red, rows 6-7 red and 8-11 blue, rows 12-15 blue.
+        assert!(data.contains(&format!("#{red}!5~-")));
+        assert!(data.contains(&format!("#{red}!5B")));
+        assert!(data.contains(&format!("#{blue}!5{{")));
+        assert!(data.contains(&format!("#{blue}!5N-")));
+    }
+
+    #[test]
+    fn encode_transparent() {
+        // A transparent column left of an opaque white one, 6 pixels high.
+        let rgba: Vec<u8> = (0..6)
+            .flat_map(|_| [0, 0, 0, 0, 255, 255, 255, 255])
+            .collect();
+        let options = SixelOptions {
+            alpha_threshold: Some(1),
+            ..SixelOptions::default()
+        };
+        let data = encode(&rgba, 2, 6, 256, &options);
+        assert_eq!("\x1bP0;1q\"1;1;2;6#0;2;100;100;100#0?~-\x1b\\", data);
+    }
+
+    #[test]
+    fn median_cut_colors() {
+        let pixels: Vec<[u8; 3]> = (0..=255).map(|v| [v, v, v]).collect();
+        let palette = median_cut(&pixels, 4, SixelSpeed::Balanced);
+        assert_eq!(4, palette.len());
+        let mut grays: Vec<u8> = palette.iter().map(|c| c[0]).collect();
+        grays.sort();
+        assert_eq!(vec![31, 95, 159, 223], grays);
+    }
+}
@@ -0,0 +1,322 @@
+//! Quality policy, to trade image quality for bandwidth.
+//!
+//! Sixel, kitty and iTerm2 encodings can be megabytes per image, which is painful over slow links
+//! such as SSH. A [Quality] set on the [crate::picker::Picker] makes every protocol it builds
+//! encode less data:
+//!
+//! * kitty and iTerm2 images are encoded with fewer pixels per cell, and the terminal scales them
+//!   up to the cells. Sixel pixels map to screen pixels, so sixel images are never downscaled.
+//! * sixel images use a smaller palette.
+//! * iTerm2 images are encoded as JPEG instead of PNG.
+//! * kitty payloads are sent as PNG, or compressed with zlib, see [KittyFormat].
+//!
+//! [crate::picker::Picker::from_termios] uses [Quality::from_env], which is
+//! [Quality::LOW_BANDWIDTH] inside of SSH sessions, and [crate::picker::Picker::new] uses
+//! [Quality::FULL]. Override either with `picker.set_quality(...)`.
+//!
+//! ```rust
+//! use ratatui_image::{picker::{Picker, ProtocolType}, quality::Quality};
+//!
+//! let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+//! picker.set_quality(Quality {
+//!     frame_budget: Some(64 * 1024),
+//!     ..Quality::LOW_BANDWIDTH
+//! });
+//! ```
+use std::borrow::Cow;
+
+use image::{imageops::FilterType, DynamicImage};
+use ratatui::layout::Rect;
+
+use crate::{protocol::kitty, FontSize, Result};
+
+/// How much image data protocols may produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Quality {
+    /// Maximum pixels per cell `(width, height)` to encode kitty and iTerm2 images with.
+    pub max_cell_pixels: Option<FontSize>,
+    /// Sixel palette size, between 64 and 256.
+    pub sixel_colors: u16,
+    /// Encode iTerm2 images as JPEG with this quality (1-100), instead of PNG.
+    pub iterm_jpeg: Option<u8>,
+    /// How kitty payloads are transmitted.
+    pub kitty_format: KittyFormat,
+    /// Target size in bytes of one image's encoding.
+    ///
+    /// iTerm2 images are encoded again with half the pixels per cell, and sixel images with half
+    /// the colors, while they exceed the budget. Kitty images are encoded with as many pixels per
+    /// cell as the budget allows uncompressed, so that the resolution only depends on the area.
+    pub frame_budget: Option<usize>,
+}
+
+impl Quality {
+    /// The best quality that the protocols can do.
+    pub const FULL: Quality = Quality {
+        max_cell_pixels: None,
+        sixel_colors: 256,
+        iterm_jpeg: None,
+        kitty_format: KittyFormat::Rgb,
+        frame_budget: None,
+    };
+
+    /// Fewer pixels and colors, lossy and compressed formats, and 256KiB per image.
+    pub const LOW_BANDWIDTH: Quality = Quality {
+        max_cell_pixels: Some((6, 12)),
+        sixel_colors: 64,
+        iterm_jpeg: Some(75),
+        kitty_format: KittyFormat::Auto,
+        frame_budget: Some(256 * 1024),
+    };
+
+    /// Smallest sixel palette, see [Quality::sixel_colors].
+    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+    const MIN_SIXEL_COLORS: u16 = 64;
+
+    /// [Quality::LOW_BANDWIDTH] if `SSH_CONNECTION` is set, [Quality::FULL] otherwise.
+    pub fn from_env() -> Quality {
+        if std::env::var_os("SSH_CONNECTION").is_some() {
+            Quality::LOW_BANDWIDTH
+        } else {
+            Quality::FULL
+        }
+    }
+
+    /// Pixels per cell to encode with, capped by [Quality::max_cell_pixels].
+    pub(crate) fn cell_pixels(&self, font_size: FontSize) -> FontSize {
+        match self.max_cell_pixels {
+            Some((width, height)) => (font_size.0.min(width), font_size.1.min(height)),
+            None => font_size,
+        }
+    }
+
+    /// Pixels per cell for an image of `rect`, so that it fits [Quality::frame_budget] given the
+    /// encoded size of one pixel.
+    pub(crate) fn cell_pixels_for_budget(
+        &self,
+        font_size: FontSize,
+        rect: Rect,
+        bytes_per_pixel: f64,
+    ) -> FontSize {
+        let (width, height) = self.cell_pixels(font_size);
+        let Some(budget) = self.frame_budget else {
+            return (width, height);
+        };
+        let pixels = rect.width as f64 * width as f64 * rect.height as f64 * height as f64;
+        let scale = (budget as f64 / (pixels * bytes_per_pixel)).sqrt();
+        if scale >= 1.0 {
+            return (width, height);
+        }
+        let scaled = |size: u16| ((size as f64 * scale) as u16).max(1);
+        (scaled(width), scaled(height))
+    }
+
+    /// Encode with `encode(cell_pixels)`, with fewer pixels per cell while the output exceeds
+    /// [Quality::frame_budget].
+    ///
+    /// The pixels per cell are scaled by the share of the budget that the last encoding took, so
+    /// that most images are encoded at most twice.
+    pub(crate) fn encode_cells_within_budget(
+        &self,
+        font_size: FontSize,
+        mut encode: impl FnMut(FontSize) -> Result<String>,
+    ) -> Result<String> {
+        let mut cell = self.cell_pixels(font_size);
+        loop {
+            let data = encode(cell)?;
+            let Some(budget) = self.frame_budget.filter(|budget| data.len() > *budget) else {
+                return Ok(data);
+            };
+            // The encoded size is roughly proportional to the pixels, and a little less to be sure.
+            let scale = (budget as f64 / data.len() as f64).sqrt() * 0.9;
+            let scaled = |size: u16| ((size as f64 * scale) as u16).max(1);
+            let next = (scaled(cell.0), scaled(cell.1));
+            if next == cell {
+                return Ok(data);
+            }
+            cell = next;
+        }
+    }
+
+    /// Encode with `encode(colors)`, halving the colors while the output exceeds
+    /// [Quality::frame_budget].
+    ///
+    /// Starts with [SixelOptions::colors], but no more than [Quality::sixel_colors].
+    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+    pub(crate) fn encode_colors_within_budget(
+        &self,
+        options: &SixelOptions,
+        mut encode: impl FnMut(u16) -> Result<String>,
+    ) -> Result<String> {
+        let sixel_colors = self.sixel_colors.clamp(Quality::MIN_SIXEL_COLORS, 256);
+        let mut colors = options.colors.clamp(2, sixel_colors);
+        let min = colors.min(Quality::MIN_SIXEL_COLORS);
+        loop {
+            let data = encode(colors)?;
+            if !self.exceeds_budget(&data) || colors == min {
+                return Ok(data);
+            }
+            colors = (colors / 2).max(min);
+        }
+    }
+
+    #[cfg(any(feature = "sixel", feature = "sixel-native"))]
+    fn exceeds_budget(&self, data: &str) -> bool {
+        self.frame_budget
+            .map_or(false, |budget| data.len() > budget)
+    }
+}
+
+/// How sixel encoders spread the error of mapping pixels to the palette.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SixelDiffusion {
+    /// Map every pixel to the nearest palette color. Keeps flat areas and hard edges, e.g. of pixel
+    /// art and screenshots.
+    None,
+    /// Bill Atkinson's method, which only diffuses 3/4 of the error, for more contrast.
+    Atkinson,
+    FloydSteinberg,
+    /// Stucki's method, spreading the error the furthest, for smooth gradients in photos.
+    #[default]
+    Stucki,
+    Burkes,
+    /// A 4x4 Bayer matrix instead of error diffusion. Stable patterns, that don't shift around when
+    /// parts of the image change.
+    Ordered,
+}
+
+/// How long sixel encoders may take to build the palette.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SixelSpeed {
+    /// Coarse palettes, built from colors with 5 bits per channel.
+    Fast,
+    #[default]
+    Balanced,
+    /// Refine the palette in extra passes over all colors.
+    Best,
+}
+
+/// Options of the sixel encoders.
+///
+/// With libsixel (the `sixel` feature), full palettes are built by libsixel, and
+/// [SixelOptions::speed] only applies to smaller palettes and [SixelDiffusion::Ordered].
+///
+/// ```rust
+/// use ratatui_image::{picker::{Picker, ProtocolType}, quality::{SixelDiffusion, SixelOptions}};
+///
+/// let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+/// // Pixel art.
+/// picker.set_sixel_options(SixelOptions {
+///     diffusion: SixelDiffusion::None,
+///     colors: 32,
+///     ..SixelOptions::default()
+/// });
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SixelOptions {
+    pub diffusion: SixelDiffusion,
+    /// Palette size, between 2 and 256. [Quality::sixel_colors] is an upper bound.
+    pub colors: u16,
+    pub speed: SixelSpeed,
+    /// Leave pixels with an alpha below the threshold unpainted, so that whatever the terminal
+    /// shows below them shows through, e.g. `Some(1)` for fully transparent pixels. Images are
+    /// padded with transparent pixels, unless there is a background color.
+    ///
+    /// `None` paints every pixel. libsixel can't leave pixels unpainted, so the native encoder is
+    /// used with a threshold.
+    pub alpha_threshold: Option<u8>,
+}
+
+impl Default for SixelOptions {
+    fn default() -> SixelOptions {
+        SixelOptions {
+            diffusion: SixelDiffusion::default(),
+            colors: 256,
+            speed: SixelSpeed::default(),
+            alpha_threshold: None,
+        }
+    }
+}
+
+/// The format of kitty payloads.
+///
+/// All formats are lossless, they trade encoding time for transmitted bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KittyFormat {
+    /// Raw RGB8 pixels (`f=24`).
+    #[default]
+    Rgb,
+    /// zlib-compressed RGB8 pixels (`f=24,o=z`).
+    Zlib,
+    /// PNG (`f=100`), which compresses better than zlib but is slower to encode.
+    Png,
+    /// [KittyFormat::Rgb] for images that fit in one transmission chunk, [KittyFormat::Png]
+    /// otherwise.
+    Auto,
+}
+
+impl KittyFormat {
+    /// Raw bytes that are transmitted in one chunk, as base64 encodes 3 bytes in 4.
+    const CHUNK_SIZE: u64 = kitty::CHUNK_SIZE as u64 / 4 * 3;
+
+    /// The format for an image of `width` × `height` pixels, never [KittyFormat::Auto].
+    pub(crate) fn resolve(self, width: u32, height: u32) -> KittyFormat {
+        match self {
+            KittyFormat::Auto if width as u64 * height as u64 * 3 <= KittyFormat::CHUNK_SIZE => {
+                KittyFormat::Rgb
+            }
+            KittyFormat::Auto => KittyFormat::Png,
+            format => format,
+        }
+    }
+}
+
+impl Default for Quality {
+    fn default() -> Quality {
+        Quality::FULL
+    }
+}
+
+/// Scale an image that was resized for `rect` down to `cell_pixels` per cell.
+pub(crate) fn downscale(
+    image: &DynamicImage,
+    rect: Rect,
+    cell_pixels: FontSize,
+) -> Cow<'_, DynamicImage> {
+    let width = (rect.width * cell_pixels.0) as u32;
+    let height = (rect.height * cell_pixels.1) as u32;
+    if image.width() <= width && image.height() <= height {
+        return Cow::Borrowed(image);
+    }
+    Cow::Owned(image.resize_exact(width, height, FilterType::Triangle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KittyFormat, Quality};
+
+    #[test]
+    fn encode_cells_within_budget() {
+        let quality = Quality {
+            frame_budget: Some(1000),
+            ..Quality::FULL
+        };
+        let mut calls = Vec::new();
+        let data = quality
+            .encode_cells_within_budget((10, 20), |cell| {
+                calls.push(cell);
+                Ok("x".repeat(cell.0 as usize * cell.1 as usize * 40))
+            })
+            .unwrap();
+        assert_eq!(calls, vec![(10, 20), (3, 6)]);
+        assert!(data.len() <= 1000);
+    }
+
+    #[test]
+    fn kitty_format_auto_chunk_boundary() {
+        // 32 × 32 RGB pixels are exactly 3072 bytes, one chunk.
+        assert_eq!(KittyFormat::Rgb, KittyFormat::Auto.resolve(32, 32));
+        assert_eq!(KittyFormat::Png, KittyFormat::Auto.resolve(32, 33));
+        assert_eq!(KittyFormat::Png, KittyFormat::Auto.resolve(1025, 1));
+        assert_eq!(KittyFormat::Rgb, KittyFormat::Auto.resolve(1024, 1));
+    }
+}
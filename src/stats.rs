@@ -0,0 +1,193 @@
+//! Render statistics, to find out where time and bandwidth go.
+//!
+//! Every protocol reports [RenderStats] about its last encoding and render, see
+//! [crate::protocol::ResizeProtocol::stats] and [crate::protocol::Protocol::stats]. States built
+//! by [crate::picker::Picker] also add up their stats in the picker's [Telemetry], see
+//! [crate::picker::Picker::stats].
+//!
+//! ```rust
+//! # use ratatui_image::picker::{Picker, ProtocolType};
+//! let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+//! let state = picker.new_state(image::DynamicImage::new_rgb8(70, 140));
+//! // ... render a few frames ...
+//! let last = state.stats();
+//! let totals = picker.stats();
+//! println!("{}B in {} renders, last encode took {:?}", totals.bytes, totals.renders, last.encode_time);
+//! ```
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Statistics of a protocol's last encoding and last render.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// Time spent resizing the image for the last encoding. Zero if it came from a cache.
+    pub resize_time: Duration,
+    /// Time spent encoding the image for the last encoding. Zero if it came from a cache.
+    pub encode_time: Duration,
+    /// Bytes of image data (escape sequences) that the last render put out.
+    ///
+    /// Zero when the terminal already has the data, as ratatui only redraws cells that changed.
+    /// Halfblocks are plain styled cells, and never count any bytes.
+    pub bytes: usize,
+    /// Whether the last render transmitted a new encoding, rather than reusing the previous one.
+    pub transmitted: bool,
+}
+
+/// Running totals of [RenderStats], see [Telemetry::totals].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TelemetryTotals {
+    /// Number of encodings, including the ones that came from a cache.
+    pub encodes: u64,
+    /// Number of renders.
+    pub renders: u64,
+    /// Number of renders that transmitted a new encoding.
+    pub transmits: u64,
+    /// Sum of [RenderStats::bytes].
+    pub bytes: u64,
+    /// Sum of [RenderStats::resize_time].
+    pub resize_time: Duration,
+    /// Sum of [RenderStats::encode_time].
+    pub encode_time: Duration,
+}
+
+/// Shared counters that states add their [RenderStats] to.
+///
+/// All clones share the same counters, so that one [Telemetry] can add up many states.
+#[derive(Clone, Debug, Default)]
+pub struct Telemetry {
+    shared: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    encodes: AtomicU64,
+    renders: AtomicU64,
+    transmits: AtomicU64,
+    bytes: AtomicU64,
+    resize_nanos: AtomicU64,
+    encode_nanos: AtomicU64,
+}
+
+impl Telemetry {
+    /// The totals since creation or the last [Telemetry::reset].
+    pub fn totals(&self) -> TelemetryTotals {
+        let counters = &self.shared;
+        TelemetryTotals {
+            encodes: counters.encodes.load(Ordering::Relaxed),
+            renders: counters.renders.load(Ordering::Relaxed),
+            transmits: counters.transmits.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            resize_time: Duration::from_nanos(counters.resize_nanos.load(Ordering::Relaxed)),
+            encode_time: Duration::from_nanos(counters.encode_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Set all totals back to zero, e.g. to measure per second.
+    pub fn reset(&self) {
+        let counters = &self.shared;
+        for counter in [
+            &counters.encodes,
+            &counters.renders,
+            &counters.transmits,
+            &counters.bytes,
+            &counters.resize_nanos,
+            &counters.encode_nanos,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Add the encoding part of `stats`.
+    pub(crate) fn record_encode(&self, stats: &RenderStats) {
+        let counters = &self.shared;
+        counters.encodes.fetch_add(1, Ordering::Relaxed);
+        counters
+            .resize_nanos
+            .fetch_add(stats.resize_time.as_nanos() as u64, Ordering::Relaxed);
+        counters
+            .encode_nanos
+            .fetch_add(stats.encode_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Add the render part of `stats`.
+    pub(crate) fn record_render(&self, stats: &RenderStats) {
+        let counters = &self.shared;
+        counters.renders.fetch_add(1, Ordering::Relaxed);
+        if stats.transmitted {
+            counters.transmits.fetch_add(1, Ordering::Relaxed);
+        }
+        counters
+            .bytes
+            .fetch_add(stats.bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Run `f`, adding the time it took to `duration`.
+pub(crate) fn timed<T>(duration: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *duration += start.elapsed();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use image::DynamicImage;
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::*;
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Resize,
+    };
+
+    #[test]
+    fn totals() {
+        let telemetry = Telemetry::default();
+        let stats = RenderStats {
+            resize_time: Duration::from_millis(2),
+            encode_time: Duration::from_millis(3),
+            bytes: 10,
+            transmitted: true,
+        };
+        telemetry.clone().record_encode(&stats);
+        telemetry.record_render(&stats);
+        telemetry.record_render(&RenderStats::default());
+        assert_eq!(
+            TelemetryTotals {
+                encodes: 1,
+                renders: 2,
+                transmits: 1,
+                bytes: 10,
+                resize_time: Duration::from_millis(2),
+                encode_time: Duration::from_millis(3),
+            },
+            telemetry.totals()
+        );
+        telemetry.reset();
+        assert_eq!(TelemetryTotals::default(), telemetry.totals());
+    }
+
+    #[test]
+    fn picker_states() {
+        let area = Rect::new(0, 0, 4, 4);
+        for protocol_type in [ProtocolType::Halfblocks, ProtocolType::Kitty] {
+            let mut picker = Picker::new((1, 1), protocol_type, None).unwrap();
+            let mut state = picker.new_state(DynamicImage::new_rgb8(4, 4));
+            for _ in 0..2 {
+                state.render(&Resize::Fit, None, area, &mut Buffer::empty(area));
+            }
+            let totals = picker.stats();
+            assert_eq!(
+                (1, 2, 1),
+                (totals.encodes, totals.renders, totals.transmits)
+            );
+            assert_eq!(state.stats().bytes, 0);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Widget and state for resizing and encoding images on a background thread.
+//!
+//! Resizing and encoding big images (especially with sixel) can take long enough to stall the
+//! UI. [ThreadProtocol] wraps any [ResizeProtocol] and hands the work to a worker thread, while
+//! the [ThreadImage] widget keeps showing the previous encoding (or a placeholder) until the new
+//! one is ready.
+//!
+//! ```rust
+//! # use ratatui::{backend::Backend, terminal::Frame};
+//! # use ratatui_image::{picker::{Picker, ProtocolType}, thread::{ThreadImage, ThreadProtocol}};
+//! # let mut picker = Picker::new((7, 14), ProtocolType::Halfblocks, None).unwrap();
+//! # let dyn_img = image::DynamicImage::new_rgb8(80, 80);
+//! struct App {
+//!     image_state: ThreadProtocol,
+//! }
+//! let (redraw_tx, redraw_rx) = std::sync::mpsc::channel::<()>();
+//! let image_state = ThreadProtocol::new(picker.new_state(dyn_img), move || {
+//!     // Wake up the event loop, which should redraw.
+//!     let _ = redraw_tx.send(());
+//! });
+//! let mut app = App { image_state };
+//!
+//! fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+//!     let image = ThreadImage::new(None);
+//!     f.render_stateful_widget(image, f.size(), &mut app.image_state);
+//! }
+//! ```
+use std::{
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use image::Rgb;
+use ratatui::{buffer::Buffer, layout::Rect, style::Style, widgets::StatefulWidget};
+
+use crate::{protocol::ResizeProtocol, stats::RenderStats, Error, Layer, Resize};
+
+/// Resizeable image widget that uses a [ThreadProtocol] state.
+///
+/// Like [crate::ResizeImage], but resizing and encoding happen on [ThreadProtocol]'s worker
+/// thread.
+pub struct ThreadImage {
+    resize: Resize,
+    background_color: Option<Rgb<u8>>,
+    placeholder: Option<(String, Style)>,
+    layer: Layer,
+    pan: (u32, u32),
+}
+
+impl ThreadImage {
+    pub fn new(background_color: Option<Rgb<u8>>) -> ThreadImage {
+        ThreadImage {
+            resize: Resize::Fit,
+            background_color,
+            placeholder: None,
+            layer: Layer::default(),
+            pan: (0, 0),
+        }
+    }
+    pub fn layer(mut self, layer: Layer) -> ThreadImage {
+        self.layer = layer;
+        self
+    }
+    /// See [ResizeProtocol::set_pan].
+    pub fn pan(mut self, pan: (u32, u32)) -> ThreadImage {
+        self.pan = pan;
+        self
+    }
+    pub fn resize(mut self, resize: Resize) -> ThreadImage {
+        self.resize = resize;
+        self
+    }
+    /// Fill the area with `symbol` and `style` while a new encoding is pending, instead of
+    /// showing the previous encoding.
+    pub fn placeholder(mut self, symbol: &str, style: Style) -> ThreadImage {
+        self.placeholder = Some((symbol.to_string(), style));
+        self
+    }
+}
+
+impl StatefulWidget for ThreadImage {
+    type State = ThreadProtocol;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+
+        state.poll();
+        state.layer = self.layer;
+        state.pan = self.pan;
+        state.inner.set_layer(self.layer);
+        state.inner.set_pan(self.pan);
+        let request = (self.resize, self.background_color, area);
+        // Don't request a failed encoding again, until the inputs or the error change.
+        let failed = state.failed.as_ref() == Some(&request) && state.inner.last_error().is_some();
+        if !state.pending && !failed && state.inner.needs_resize(&request.0, area).is_some() {
+            state.request(request);
+        }
+
+        let rect = state.inner.rect();
+        let fits = rect.width <= area.width && rect.height <= area.height;
+        match (&self.placeholder, state.pending) {
+            (Some((symbol, style)), true) => {
+                for y in area.top()..area.bottom() {
+                    for x in area.left()..area.right() {
+                        buf.get_mut(x, y).set_symbol(symbol).set_style(*style);
+                    }
+                }
+            }
+            // A previous encoding that is larger than the area would overdraw.
+            _ if fits && rect.width > 0 => state.inner.render_current(area, buf),
+            _ => {}
+        }
+    }
+}
+
+type RequestInputs = (Resize, Option<Rgb<u8>>, Rect);
+
+struct ResizeRequest {
+    protocol: Box<dyn ResizeProtocol>,
+    resize: Resize,
+    background_color: Option<Rgb<u8>>,
+    area: Rect,
+}
+
+/// State for [ThreadImage], wrapping a [ResizeProtocol] that is resized on a worker thread.
+///
+/// The worker thread is spawned in [ThreadProtocol::new] and stops when the state is dropped.
+pub struct ThreadProtocol {
+    inner: Box<dyn ResizeProtocol>,
+    pending: bool,
+    /// The inputs of the pending request, and of the last one if it failed
+    requested: Option<RequestInputs>,
+    failed: Option<RequestInputs>,
+    /// Changes to re-apply to the encoding from the worker, which was cloned before them
+    reset: bool,
+    layer: Layer,
+    pan: (u32, u32),
+    tx: Sender<ResizeRequest>,
+    rx: Receiver<Box<dyn ResizeProtocol>>,
+}
+
+impl ThreadProtocol {
+    /// Wrap a [ResizeProtocol] and spawn its worker thread.
+    ///
+    /// `on_ready` is called from the worker thread every time a new encoding is ready, and should
+    /// be used to tell the application to redraw, for example by sending an event to its loop.
+    pub fn new<F>(inner: Box<dyn ResizeProtocol>, on_ready: F) -> ThreadProtocol
+    where
+        F: Fn() + Send + 'static,
+    {
+        let (tx, worker_rx) = mpsc::channel::<ResizeRequest>();
+        let (worker_tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(mut request) = worker_rx.recv() {
+                request.protocol.resize_encode(
+                    &request.resize,
+                    request.background_color,
+                    request.area,
+                );
+                if worker_tx.send(request.protocol).is_err() {
+                    break;
+                }
+                on_ready();
+            }
+        });
+        ThreadProtocol {
+            inner,
+            pending: false,
+            requested: None,
+            failed: None,
+            reset: false,
+            layer: Layer::default(),
+            pan: (0, 0),
+            tx,
+            rx,
+        }
+    }
+
+    /// Whether a resize and encode is currently being done on the worker thread.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Reset the wrapped state, see [ResizeProtocol::reset].
+    pub fn reset(&mut self) {
+        self.inner.reset();
+        self.reset = self.pending;
+        self.failed = None;
+    }
+
+    /// Remove the image from where it was last rendered, see [ResizeProtocol::clear].
+    pub fn clear(&mut self, buf: &mut Buffer) {
+        self.inner.clear(buf);
+    }
+
+    /// Statistics of the wrapped state, see [ResizeProtocol::stats].
+    ///
+    /// The encoding times are measured on the worker thread.
+    pub fn stats(&self) -> RenderStats {
+        self.inner.stats()
+    }
+
+    /// The error of the wrapped state, see [ResizeProtocol::last_error].
+    pub fn last_error(&self) -> Option<&Error> {
+        self.inner.last_error()
+    }
+
+    /// Swap in the latest finished encoding, if any.
+    fn poll(&mut self) {
+        while let Ok(mut protocol) = self.rx.try_recv() {
+            protocol.set_layer(self.layer);
+            protocol.set_pan(self.pan);
+            if std::mem::take(&mut self.reset) {
+                protocol.reset();
+            }
+            self.failed = match protocol.last_error() {
+                Some(_) => self.requested.take(),
+                None => None,
+            };
+            self.inner = protocol;
+            self.pending = false;
+        }
+    }
+
+    fn request(&mut self, (resize, background_color, area): RequestInputs) {
+        self.requested = Some((resize.clone(), background_color, area));
+        let request = ResizeRequest {
+            protocol: dyn_clone::clone_box(&*self.inner),
+            resize,
+            background_color,
+            area,
+        };
+        self.pending = self.tx.send(request).is_ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        time::Duration,
+    };
+
+    use image::{DynamicImage, Rgb, RgbImage};
+    use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+    use super::{ThreadImage, ThreadProtocol};
+    use crate::{
+        picker::{Picker, ProtocolType},
+        protocol::ResizeProtocol,
+        Error, Resize,
+    };
+
+    /// Fails every encoding, counting the attempts.
+    #[derive(Clone)]
+    struct Failing {
+        encodes: Arc<AtomicUsize>,
+        error: Option<Arc<Error>>,
+    }
+
+    impl ResizeProtocol for Failing {
+        fn rect(&self) -> Rect {
+            Rect::default()
+        }
+        fn needs_resize(&self, _resize: &Resize, area: Rect) -> Option<Rect> {
+            Some(area)
+        }
+        fn resize_encode(
+            &mut self,
+            _resize: &Resize,
+            _background_color: Option<Rgb<u8>>,
+            _area: Rect,
+        ) {
+            self.encodes.fetch_add(1, Ordering::SeqCst);
+            self.error = Some(Arc::new(Error::Timeout));
+        }
+        fn last_error(&self) -> Option<&Error> {
+            self.error.as_deref()
+        }
+    }
+
+    #[test]
+    fn pending_then_ready() {
+        let mut picker = Picker::new((1, 2), ProtocolType::Halfblocks, None).unwrap();
+        let image = RgbImage::from_pixel(4, 8, Rgb([255, 0, 0]));
+        let (tx, rx) = mpsc::channel();
+        let mut state = ThreadProtocol::new(
+            picker.new_state(DynamicImage::ImageRgb8(image)),
+            move || {
+                let _ = tx.send(());
+            },
+        );
+        let area = Rect::new(0, 0, 4, 4);
+
+        let mut buf = Buffer::empty(area);
+        ThreadImage::new(None)
+            .placeholder("?", Default::default())
+            .render(area, &mut buf, &mut state);
+        assert!(state.is_pending());
+        assert_eq!("?", buf.get(0, 0).symbol);
+
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let mut buf = Buffer::empty(area);
+        ThreadImage::new(None)
+            .placeholder("?", Default::default())
+            .render(area, &mut buf, &mut state);
+        assert!(!state.is_pending());
+        assert_eq!("▀", buf.get(0, 0).symbol);
+    }
+
+    #[test]
+    fn failed_not_requested_again() {
+        let encodes = Arc::new(AtomicUsize::new(0));
+        let failing = Failing {
+            encodes: encodes.clone(),
+            error: None,
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut state = ThreadProtocol::new(Box::new(failing), move || {
+            let _ = tx.send(());
+        });
+        let render = |state: &mut ThreadProtocol, area: Rect| {
+            ThreadImage::new(None).render(area, &mut Buffer::empty(area), state);
+        };
+        let area = Rect::new(0, 0, 4, 4);
+
+        render(&mut state, area);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        for _ in 0..3 {
+            render(&mut state, area);
+            assert!(!state.is_pending());
+        }
+        assert_eq!(1, encodes.load(Ordering::SeqCst));
+        assert!(state.last_error().is_some());
+
+        // Other inputs are requested.
+        render(&mut state, Rect::new(0, 0, 2, 2));
+        assert!(state.is_pending());
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(2, encodes.load(Ordering::SeqCst));
+    }
+}